@@ -0,0 +1,73 @@
+use log::*;
+
+use crate::{state::State, ControlMessage, OnState};
+
+/// Tracks the latching all-off override shared by every light. While it's set the light is turned off, and once it's
+/// cleared the light is turned back to what it was, unless it's been changed by hand in the meanwhile.
+#[derive(Debug, Default)]
+pub struct AllOff {
+    active: bool,
+    restore: Option<OnState>,
+}
+
+impl AllOff {
+    /// Processes a payload from the all-off topic and returns the command to apply for it, if any. Clearing the
+    /// retained payload clears the override.
+    pub fn update(&mut self, payload: &[u8], state: &State) -> Option<ControlMessage> {
+        let payload = String::from_utf8_lossy(payload);
+        let active = match payload.trim().to_ascii_lowercase().as_str() {
+            "on" | "true" | "1" => true,
+            "" | "off" | "false" | "0" => false,
+            _ => {
+                warn!("Ignoring unknown all-off payload: {payload}");
+                return None;
+            }
+        };
+
+        match (self.active, active) {
+            (false, true) => {
+                info!("All-off override set");
+                self.activate(state)
+            }
+            (true, false) => {
+                info!("All-off override cleared");
+                self.active = false;
+
+                let state = self.restore.take()?;
+                Some(ControlMessage {
+                    state: Some(state),
+                    ..Default::default()
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the command to turn the light off again if the override is set when the light's state is restored,
+    /// since the restored state is what should come back once the override is cleared
+    pub fn state_restored(&mut self, state: &State) -> Option<ControlMessage> {
+        if self.active {
+            self.activate(state)
+        } else {
+            None
+        }
+    }
+
+    /// Forgets the state to return to when the light is changed by hand while the override is set, so clearing the
+    /// override doesn't undo the change
+    pub fn changed_by_hand(&mut self) {
+        if self.active && self.restore.take().is_some() {
+            info!("Light changed during the all-off override, it won't be restored when the override is cleared");
+        }
+    }
+
+    fn activate(&mut self, state: &State) -> Option<ControlMessage> {
+        self.active = true;
+        self.restore = Some(state.state);
+
+        Some(ControlMessage {
+            state: Some(OnState::Off),
+            ..Default::default()
+        })
+    }
+}
@@ -0,0 +1,88 @@
+use log::*;
+use serde_json::Value;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+
+use crate::http;
+
+// how many requests can wait for the light's loop to get to them before new ones wait to be sent
+const API_QUEUE_SIZE: usize = 8;
+
+/// A request for the light's loop to handle, since it owns the state. Setting the state takes the same JSON as the
+/// command topic
+#[derive(Debug)]
+pub struct ApiRequest {
+    /// The command to apply, or none to only get the current state
+    pub command: Option<Vec<u8>>,
+    /// Where to send the state after handling the request, or why the command failed
+    pub reply: oneshot::Sender<Result<Value, String>>,
+}
+
+/// Starts serving the REST API on a port in a task of its own, returning the receiver the requests to handle arrive
+/// through
+pub async fn serve(port: u16) -> anyhow::Result<mpsc::Receiver<ApiRequest>> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    let (sender, receiver) = mpsc::channel(API_QUEUE_SIZE);
+    info!("Serving the REST API on port {port}");
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept API connection: {e}");
+                    continue;
+                }
+            };
+
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, sender).await {
+                    debug!("API request failed: {e}");
+                }
+            });
+        }
+    });
+
+    Ok(receiver)
+}
+
+async fn handle_connection(mut stream: TcpStream, sender: mpsc::Sender<ApiRequest>) -> anyhow::Result<()> {
+    let request = http::read_request(&mut stream).await?;
+
+    let command = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/state") => None,
+        ("PUT", "/state") => Some(request.body),
+        (_, "/state") => {
+            return http::write_response(
+                &mut stream,
+                "405 Method Not Allowed",
+                "text/plain",
+                b"Method not allowed\n",
+            )
+            .await
+        }
+        _ => return http::write_response(&mut stream, "404 Not Found", "text/plain", b"Not found\n").await,
+    };
+
+    let (reply, response) = oneshot::channel();
+    sender.send(ApiRequest { command, reply }).await?;
+
+    match response.await? {
+        Ok(state) => {
+            let body = serde_json::to_vec(&state)?;
+            http::write_response(&mut stream, "200 OK", "application/json", &body).await
+        }
+        Err(e) => {
+            http::write_response(
+                &mut stream,
+                "400 Bad Request",
+                "text/plain",
+                format!("{e}\n").as_bytes(),
+            )
+            .await
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use serde::Serialize;
+
+use crate::{config::Config, state::Mode};
+
+/// The colour modes the light supports, as Home Assistant names them
+pub const COLOR_MODES: &[&str] = &["hs", "color_temp"];
+
+/// A report of what the light supports in this build and configuration, for frontends that adapt to the light
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    modes: Vec<&'static str>,
+    mode_labels: Vec<String>,
+    color_modes: &'static [&'static str],
+    white_channels: u8,
+    brightness: bool,
+    transition: bool,
+}
+
+impl Capabilities {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            modes: Mode::ALL.iter().map(Mode::name).collect(),
+            mode_labels: Mode::ALL.iter().map(|m| config.mode_label(*m).to_string()).collect(),
+            color_modes: COLOR_MODES,
+            white_channels: u8::from(config.pin_w.is_some()),
+            brightness: true,
+            transition: true,
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use chrono::Local;
+use log::*;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{config::Config, state::State};
+
+#[derive(Debug, Serialize)]
+struct ChangeLogEntry<'a> {
+    timestamp: String,
+    source: &'a str,
+    old: Value,
+    new: Value,
+}
+
+/// Appends a state change to the change log, if one is configured. The log is a JSON line per change. When it would
+/// grow past its maximum size, it's rotated to a file with `.1` appended to its name, replacing any earlier rotated
+/// log, so at most twice the maximum size is used. Failing to write the log is only logged.
+pub async fn append(config: &Config, source: &str, old: &State, new: &State) {
+    let Some(path) = &config.change_log else {
+        return;
+    };
+
+    let old = serde_json::to_value(old).expect("failed to serialise state");
+    let new = serde_json::to_value(new).expect("failed to serialise state");
+
+    // commands may not change anything, e.g. when they're held off or queued
+    if old == new {
+        return;
+    }
+
+    let entry = ChangeLogEntry {
+        timestamp: Local::now().to_rfc3339(),
+        source,
+        old,
+        new,
+    };
+
+    let mut line = serde_json::to_vec(&entry).expect("failed to serialise change log entry");
+    line.push(b'\n');
+
+    if let Err(e) = write_line(path, &line, config.change_log_max_size).await {
+        warn!("Failed to write to change log {}: {e}", path.display());
+    }
+}
+
+async fn write_line(path: &Path, line: &[u8], max_size: u64) -> anyhow::Result<()> {
+    let size = match fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+
+    if size > 0 && size + line.len() as u64 > max_size {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+
+        debug!("Rotating change log {} to {rotated:?}", path.display());
+        fs::rename(path, rotated).await?;
+    }
+
+    let mut log = fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    log.write_all(line).await?;
+    Ok(())
+}
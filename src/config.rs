@@ -1,41 +1,739 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, env, fs, net::SocketAddr, path::PathBuf};
 
+use chrono::NaiveTime;
 use log::*;
-use serde::Deserialize;
+use rumqttc::v5::mqttbytes::{self, QoS};
+use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    presence::PresenceAction,
+    state::{ColorInterpolation, Easing, Mode, State},
+    template::StateTemplate,
+    Color,
+};
 
 const DEFAULT_MQTT_TOPIC: &str = "moodlight";
 const DEFAULT_HOME_ASSISTANT_MQTT_TOPIC: &str = "homeassistant";
 const ENV_PREFIX: &str = "MOODLIGHT_";
+const CONFIG_FILE_VAR: &str = "MOODLIGHT_CONFIG_FILE";
+const DEFAULT_CONFIG_FILE: &str = "/etc/moodlight.toml";
+// the config file's table of named hardware profiles, and the setting selecting one of them
+const PROFILES_TABLE: &str = "profile";
+const PROFILE_VAR: &str = "MOODLIGHT_PROFILE";
+// the config file's list of more settings to protect like the ones below. it's only read from the file
+const FILE_ONLY_SETTING: &str = "MOODLIGHT_FILE_ONLY";
+// the settings the environment can't override once the config file sets them, so a locked-down deployment's broker
+// and hardware stay the way the file has them. a light's own settings in its table are protected the same
+const FILE_ONLY_SETTINGS: &[&str] = &[
+    "BROKER_URL",
+    "BROKER_USERNAME",
+    "BROKER_PASSWORD",
+    "BROKER_TLS",
+    "BROKER_CA",
+    "LIGHT_SINK",
+    "BLASTER",
+    "PWM_CHIP",
+    "WS2812_DEVICE",
+    "PIN_R",
+    "PIN_G",
+    "PIN_B",
+    "PIN_W",
+];
+// the highest BCM GPIO pin number on the Pi's header
+const MAX_GPIO_PIN: u8 = 27;
+
+/// What to do with a colour in a command while the light is, and stays, in the rainbow mode. Any other combination of
+/// current mode, commanded mode and colour always updates the colour.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RainbowColorPolicy {
+    /// Discard the colour and log a warning about it
+    Ignore,
+    /// Set the rainbow's current colour, so the sweep continues from it
+    Apply,
+    /// Pause the sweep at the colour by switching to the static mode, unless the command explicitly keeps the rainbow
+    /// mode, in which case the colour is applied
+    Pause,
+}
+
+/// What to do with a command arriving while a transition is in progress
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransitionOverlap {
+    /// Apply the command right away. A transition it starts continues from wherever the previous one got to
+    Cancel,
+    /// Apply the command only once the transition completes
+    Queue,
+}
+
+/// What to do with a scheduled command whose time has already passed, either when it's received or when the light
+/// starts
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PastSchedulePolicy {
+    Fire,
+    Ignore,
+}
+
+/// What drives the light's channels
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LightSinkKind {
+    /// pi-blaster's FIFO, with the pins being GPIO pins
+    Blaster,
+    /// The kernel's sysfs PWM interface, with the pins being the PWM chip's channels
+    Pwm,
+    /// A WS2812 strip on an SPI bus, with every LED showing the same colour. The pins aren't used
+    Ws2812,
+}
+
+/// One of the light's colour channels
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    White,
+}
+
+/// How Home Assistant tells whether the light is available from its availability topics
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AvailabilityMode {
+    /// Available only if every topic says so
+    All,
+    /// Available if any topic says so
+    Any,
+    /// Available if the topic published to last says so, which is the only one with the light's single topic
+    #[default]
+    Latest,
+}
+
+/// The format of the log lines
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Plain text for humans
+    #[default]
+    Plain,
+    /// A JSON object per line for log collectors
+    Json,
+}
 
+/// The logging settings, read on their own before the logger is set up and the rest of the config is read
 #[derive(Debug, Deserialize)]
+struct LogSettings {
+    #[serde(default)]
+    log_format: LogFormat,
+}
+
+/// The lights to run over a single connection, read before the lights' own configs
+#[derive(Debug, Deserialize)]
+struct LightList {
+    #[serde(default)]
+    lights: Vec<String>,
+}
+
+/// The config file's named hardware profiles by their names, each with its settings without the prefix
+type Profiles = HashMap<String, HashMap<String, String>>;
+
+/// The settings that changed when reloading the config
+#[derive(Debug, Default)]
+pub struct ReloadedSettings {
+    /// The settings that took effect right away
+    pub applied: Vec<&'static str>,
+    /// The settings that were left as they were, since they only take effect after a restart
+    pub need_restart: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub name: String,
+    /// The names of the lights to run over a single connection to the broker, if more than the one light. Each light's
+    /// settings are the shared ones overridden by any settings prefixed with its name
+    #[serde(default)]
+    pub lights: Vec<String>,
     #[serde(default = "default_mqtt_topic")]
     pub mqtt_topic: String,
     pub broker_username: String,
     pub broker_password: String,
     pub broker_url: String,
-    #[serde(default = "default_mqtt_port")]
-    pub broker_port: u16,
+    /// Whether to connect to the broker with TLS even if the URL isn't an mqtts:// one. The port still has to be given
+    /// in the URL if it's not the default for the URL's scheme
+    #[serde(default)]
+    pub broker_tls: bool,
+    /// Path to a PEM file of the CA certificates to trust for the broker instead of the system's. Implies TLS
+    #[serde(default)]
+    pub broker_ca: Option<PathBuf>,
+    /// How long in seconds the broker can stay silent before connecting to it again. The keep-alive pings get a
+    /// response every 10 seconds, so a silence longer than that means the connection's stalled without an error
+    #[serde(default = "default_connection_watchdog")]
+    pub connection_watchdog: f32,
+    #[serde(default = "default_light_sink")]
+    pub light_sink: LightSinkKind,
+    /// Whether to only log the output instead of writing it to the light sink, for running without the hardware
+    #[serde(default)]
+    pub dry_run: bool,
     #[serde(default = "default_blaster")]
     pub blaster: PathBuf,
+    /// The sysfs directory of the PWM chip to drive with the PWM sink
+    #[serde(default = "default_pwm_chip")]
+    pub pwm_chip: PathBuf,
+    /// The PWM sink's period in nanoseconds
+    #[serde(default = "default_pwm_period")]
+    pub pwm_period: u32,
+    /// The spidev device of the SPI bus the WS2812 strip's data line is on
+    #[serde(default = "default_ws2812_device")]
+    pub ws2812_device: PathBuf,
+    /// How many LEDs the WS2812 strip has. Required with the WS2812 sink
+    #[serde(default)]
+    pub ws2812_leds: Option<u16>,
     pub pin_r: u8,
     pub pin_g: u8,
     pub pin_b: u8,
+    /// The pin of a separate white channel, if the strip has one
+    #[serde(default)]
+    pub pin_w: Option<u8>,
+    /// Whether the channels' duty cycles are inverted, for common-anode LEDs which are fully on with their pin low
+    #[serde(default)]
+    pub invert_pwm: bool,
+    /// The channels inverted on their own, for boards wired with some channels common-anode and the rest
+    /// common-cathode. Given as comma-separated channel names, e.g. `red,white`
+    #[serde(default)]
+    pub invert_channels: Vec<Channel>,
     #[serde(default = "default_step_duration")]
     pub step_duration: f32,
+    #[serde(default = "default_rainbow_color_policy")]
+    pub rainbow_color: RainbowColorPolicy,
+    /// The hue the rainbow restarts from when entering the rainbow mode. Without it, the rainbow resumes from the
+    /// current colour
+    #[serde(default)]
+    pub rainbow_start_hue: Option<f32>,
+    /// The fraction of the pulse mode's cycle spent rising, the rest of it is spent falling
+    #[serde(default = "default_pulse_rise")]
+    pub pulse_rise: f32,
+    #[serde(default = "default_transition_overlap")]
+    pub transition_overlap: TransitionOverlap,
+    /// Whether commands retained on the command topics are ignored instead of applied with a warning
+    #[serde(default)]
+    pub ignore_retained_commands: bool,
+    /// Whether to subscribe with the no-local option so the broker doesn't send the light its own publishes. Some
+    /// brokers don't support it, in which case the light recognises its own publishes itself
+    #[serde(default = "default_no_local")]
+    pub no_local: bool,
+    /// Whether a command with any invalid field is rejected as a whole instead of applying its valid fields
+    #[serde(default)]
+    pub strict_commands: bool,
+    /// How often in seconds to write the current colour to the blaster again while nothing is animating, in case
+    /// something else has touched the pins
+    #[serde(default)]
+    pub refresh_interval: Option<f32>,
+    /// How long in seconds turning the light on or off takes by default
+    #[serde(default = "default_transition_duration")]
+    pub transition_duration: f32,
+    /// How a transition's brightness follows its progress by default
+    #[serde(default)]
+    pub transition_easing: Easing,
+    /// Whether a transition fades the brightness and the colour by default. Either can snap to its target while the
+    /// other fades, and the light being turned on or off is a change in the brightness. Both fade by default
+    #[serde(default = "default_transition_brightness")]
+    pub transition_brightness: bool,
+    #[serde(default = "default_transition_color")]
+    pub transition_color: bool,
+    /// How a transition's colour gets to its target by default
+    #[serde(default)]
+    pub color_interpolation: ColorInterpolation,
+    /// Whether a transition's length scales with how much the brightness or the colour changes, so the full length is
+    /// only taken by a change across the whole range of the brightness, the hue or the saturation
+    #[serde(default)]
+    pub scale_transitions: bool,
+    /// The shortest and longest a scaled transition can be, in seconds
+    #[serde(default)]
+    pub min_transition_duration: f32,
+    #[serde(default)]
+    pub max_transition_duration: Option<f32>,
+    /// How long in seconds fading to black takes when the light is turned off, if different from turning it on
+    #[serde(default)]
+    pub off_transition_duration: Option<f32>,
+    /// Whether a command setting the brightness without setting the on state turns the light on if it's off, like
+    /// Home Assistant does. Otherwise only the stored brightness changes
+    #[serde(default = "default_brightness_implies_on")]
+    pub brightness_implies_on: bool,
+    /// How long in seconds a command with a priority holds off commands with a lower priority
+    #[serde(default = "default_priority_hold")]
+    pub priority_hold: f32,
+    #[serde(default = "default_past_schedule_policy")]
+    pub past_schedule: PastSchedulePolicy,
+    #[serde(default)]
+    pub udp_sink: Option<SocketAddr>,
+    /// The port to serve Prometheus metrics on. Without it, the metrics aren't served
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+    /// The port to serve the local REST API on. Without it, the API isn't served
+    #[serde(default)]
+    pub api_port: Option<u16>,
+    /// Also accept bare integer brightness commands on the brightness command topic, like the non-JSON light schema
+    #[serde(default)]
+    pub legacy_brightness_topic: bool,
+    /// The maximum sum of the channels' duty cycles, between 0 (exclusive) and 3. Colours exceeding it are dimmed to
+    /// fit
+    #[serde(default)]
+    pub power_budget: Option<f32>,
+    /// Gamma correction applied to each channel, between 1 (none) and 3
+    #[serde(default = "default_gamma")]
+    pub gamma: f32,
+    /// Factors between 0 and 1 each channel is scaled with to calibrate the white balance, e.g. for red LEDs brighter
+    /// than the others
+    #[serde(default = "default_channel_scale")]
+    pub scale_r: f32,
+    #[serde(default = "default_channel_scale")]
+    pub scale_g: f32,
+    #[serde(default = "default_channel_scale")]
+    pub scale_b: f32,
+    /// Semicolon-separated hue,saturation colours the palette mode cycles through
+    #[serde(default, deserialize_with = "deserialize_palette")]
+    pub palette: Vec<Color>,
+    /// Exponent the commanded brightness is raised to, to tune how the brightness slider feels. Above 1 gives the dim
+    /// end more of the slider, below 1 gives it less. It's applied before the brightness curve
+    #[serde(default)]
+    pub brightness_knee: Option<f32>,
+    /// Comma-separated output brightnesses for evenly spaced commanded brightnesses, from 0 to 1. Commanded
+    /// brightnesses between the points are interpolated
+    #[serde(default)]
+    pub brightness_curve: Option<Vec<f32>>,
+    /// The lowest brightness shown while the light is on, between 0 and 1, for drivers that flicker or show nothing
+    /// at low brightnesses
+    #[serde(default)]
+    pub min_brightness: Option<f32>,
+    /// The lowest saturation shown, between 0 and 100
+    #[serde(default)]
+    pub min_saturation: Option<f32>,
+    /// Publish the current colour as a `#RRGGBB` string to the hex topic
+    #[serde(default)]
+    pub publish_hex: bool,
+    /// A JSON template for the shape of the state published to the templated state topic
+    #[serde(default)]
+    pub state_template: Option<StateTemplate>,
+    /// Where to log every state change as JSON lines
+    #[serde(default)]
+    pub change_log: Option<PathBuf>,
+    /// The size in bytes the change log is rotated at
+    #[serde(default = "default_change_log_max_size")]
+    pub change_log_max_size: u64,
+    /// Path to a local file the state is saved to along with the state topic. It's restored from if no state arrives
+    /// from the state topic within the timeout, in seconds, after starting
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    #[serde(default = "default_state_file_timeout")]
+    pub state_file_timeout: f32,
+    /// The colour, given as `hue,saturation`, shown as an alarm when exiting due to an error
+    #[serde(default, deserialize_with = "deserialize_optional_color")]
+    pub panic_color: Option<Color>,
 
+    /// How long in seconds the presence and scheduled commands are held off after the light's changed by a command
+    #[serde(default)]
+    pub manual_hold: Option<f32>,
+    /// The local times of day, as HH:MM, the light is turned on and off at every day
+    #[serde(default, deserialize_with = "deserialize_optional_time")]
+    pub on_time: Option<NaiveTime>,
+    #[serde(default, deserialize_with = "deserialize_optional_time")]
+    pub off_time: Option<NaiveTime>,
+    /// The local time of day, as HH:MM, the daily sunrise starts at
+    #[serde(default, deserialize_with = "deserialize_optional_time")]
+    pub sunrise_time: Option<NaiveTime>,
+    /// How long in seconds the sunrise takes to reach full brightness
+    #[serde(default = "default_sunrise_duration")]
+    pub sunrise_duration: f32,
+    /// The local times of day, as HH:MM, the night starts and ends at. During the night the brightness shown is
+    /// capped, so no command can turn the light brighter than that. The commanded brightness is kept and shown again
+    /// once the night ends
+    #[serde(default, deserialize_with = "deserialize_optional_time")]
+    pub night_start: Option<NaiveTime>,
+    #[serde(default, deserialize_with = "deserialize_optional_time")]
+    pub night_end: Option<NaiveTime>,
+    /// The highest brightness, between 0 and 255, during the night
+    #[serde(default = "default_night_brightness")]
+    pub night_brightness: u8,
+    /// Retained topic shared by all lights that turns them all off while it's set, and back when it's cleared
+    #[serde(default)]
+    pub all_off_topic: Option<String>,
+    /// Topic of a room-presence sensor to follow
+    #[serde(default)]
+    pub presence_topic: Option<String>,
+    #[serde(default = "default_presence_empty_action")]
+    pub presence_empty_action: PresenceAction,
+    /// The brightness the light is dimmed to when the room is empty and the action is to dim
+    #[serde(default = "default_presence_dim_brightness")]
+    pub presence_dim_brightness: u8,
+    /// How long in seconds the presence has to stay the same before it's acted on
+    #[serde(default = "default_presence_debounce")]
+    pub presence_debounce: f32,
+
+    /// Labels shown for the modes in Home Assistant, given as comma-separated `Mode:Label` pairs. Modes without a
+    /// label are shown with their own name.
+    #[serde(default, deserialize_with = "deserialize_mode_labels")]
+    pub mode_labels: Vec<(Mode, String)>,
+
+    /// Whether the light is announced to Home Assistant. Without it, any discovery configs sent earlier are cleared
+    #[serde(default = "default_home_assistant_discovery")]
+    pub home_assistant_discovery: bool,
+    /// Whether the discovery configs are cleared when shutting down, removing the light from Home Assistant, e.g. when
+    /// retiring it
+    #[serde(default)]
+    pub clear_discovery_on_shutdown: bool,
     #[serde(default = "default_home_assistant_topic")]
     pub home_assistant_topic: String,
+    /// The area Home Assistant suggests for the light's device
+    #[serde(default)]
+    pub suggested_area: Option<String>,
+    /// The manufacturer and the model shown on the light's device page in Home Assistant
+    #[serde(default)]
+    pub device_manufacturer: Option<String>,
+    #[serde(default)]
+    pub device_model: Option<String>,
+    /// A link to the light's configuration, shown on its device page in Home Assistant
+    #[serde(default)]
+    pub configuration_url: Option<String>,
+    /// The QoS level, from 0 to 2, the discovery configs are published with
+    #[serde(default = "default_discovery_qos", deserialize_with = "deserialize_qos")]
+    pub discovery_qos: QoS,
+    #[serde(default = "default_retain")]
+    pub discovery_retain: bool,
+    /// The QoS level the state, the templated state and the hex colour are published with. With 2, the state saved on
+    /// shutdown is stored exactly once
+    #[serde(default = "default_state_qos", deserialize_with = "deserialize_qos")]
+    pub state_qos: QoS,
+    /// Whether the state is retained. Without it, the light can't restore its state from the state topic on startup,
+    /// only from the state file
+    #[serde(default = "default_retain")]
+    pub state_retain: bool,
+    /// The shortest time in seconds between publishing the state, to not flood the broker with a burst of commands,
+    /// e.g. from dragging a slider. The last state of a burst is always published once the time is over
+    #[serde(default)]
+    pub state_publish_interval: Option<f32>,
+    /// The QoS level the availability, including the last will, is published with
+    #[serde(default = "default_availability_qos", deserialize_with = "deserialize_qos")]
+    pub availability_qos: QoS,
+    #[serde(default = "default_retain")]
+    pub availability_retain: bool,
+    /// The availability mode the discovery configs give Home Assistant
+    #[serde(default)]
+    pub availability_mode: AvailabilityMode,
+    /// The optional node ID level in the discovery topics, to group the light's discovery configs
+    #[serde(default)]
+    pub discovery_node_id: Option<String>,
+    /// The object ID level in the discovery topics. Defaults to the light's unique ID
+    #[serde(default)]
+    pub discovery_object_id: Option<String>,
+}
+
+/// Reads the log format, which has to be known before anything's logged
+pub fn log_format() -> anyhow::Result<LogFormat> {
+    let (vars, _) = config_vars()?;
+    let LogSettings { log_format } = envy::prefixed(ENV_PREFIX).from_iter(vars)?;
+    Ok(log_format)
 }
 
 impl Config {
-    pub fn load() -> anyhow::Result<Self> {
-        let config = envy::prefixed(ENV_PREFIX).from_env::<Config>()?;
+    /// Loads the config of every light. Without a list of lights there's only the one light. With one, a light's own
+    /// settings are prefixed with its name, like `MOODLIGHT_DESK_PIN_R` or a `[desk]` table in the config file for a
+    /// light named desk. A light's hardware can come from one of the config file's profiles, selected by its name with
+    /// `MOODLIGHT_PROFILE` or the light's own `MOODLIGHT_DESK_PROFILE`
+    pub fn load_lights() -> anyhow::Result<Vec<Self>> {
+        let (vars, profiles) = config_vars()?;
+        let LightList { lights } = envy::prefixed(ENV_PREFIX).from_iter(vars.clone())?;
+
+        if lights.is_empty() {
+            return Ok(vec![Self::from_vars(with_profile(vars, &profiles)?)?]);
+        }
+
+        let configs = lights
+            .iter()
+            .map(|name| {
+                let prefix = format!("{ENV_PREFIX}{}_", light_var_name(name));
+                let mut light_vars = vars.clone();
+                light_vars.insert(format!("{ENV_PREFIX}NAME"), name.clone());
+
+                for (key, value) in &vars {
+                    if let Some(setting) = key.strip_prefix(&prefix) {
+                        light_vars.insert(format!("{ENV_PREFIX}{setting}"), value.clone());
+                    }
+                }
+
+                with_profile(light_vars, &profiles)
+                    .and_then(Self::from_vars)
+                    .map_err(|e| e.context(format!("invalid config for light '{name}'")))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // the lights share the connection and with it the last will, so they share the topic it's in too
+        for (i, config) in configs.iter().enumerate() {
+            let earlier = &configs[..i];
+
+            anyhow::ensure!(
+                config.mqtt_topic == configs[0].mqtt_topic,
+                "light '{}' has a different MQTT topic than '{}'. The lights share a connection, so they share the \
+                 topic as well",
+                config.name,
+                configs[0].name
+            );
+            anyhow::ensure!(
+                earlier.iter().all(|other| other.unique_id() != config.unique_id()),
+                "light '{}' has the same name as an earlier light",
+                config.name
+            );
+
+            for (what, port) in [("metrics", config.metrics_port), ("API", config.api_port)] {
+                anyhow::ensure!(
+                    port.is_none()
+                        || earlier
+                            .iter()
+                            .all(|other| other.metrics_port != port && other.api_port != port),
+                    "light '{}' has the same {what} port as an earlier light",
+                    config.name
+                );
+            }
+        }
+
+        Ok(configs)
+    }
+
+    fn from_vars(vars: HashMap<String, String>) -> anyhow::Result<Self> {
+        let config = envy::prefixed(ENV_PREFIX).from_iter::<_, Config>(vars)?;
         debug!("{config:?}");
+
+        // the light's own topics and the discovery topics living under the same namespace risks the light's messages
+        // being taken as discovery configs or the other way around, which could have the light react to itself
+        anyhow::ensure!(
+            !topic_namespaces_overlap(&config.mqtt_topic, &config.home_assistant_topic),
+            "MQTT topic '{}' and Home Assistant topic '{}' overlap. Neither can be the same as or nested under the \
+             other",
+            config.mqtt_topic,
+            config.home_assistant_topic
+        );
+
+        let pins = [
+            Some(("red", config.pin_r)),
+            Some(("green", config.pin_g)),
+            Some(("blue", config.pin_b)),
+            config.pin_w.map(|pin| ("white", pin)),
+        ];
+        // the WS2812 sink doesn't use the pins at all
+        let pins: Vec<_> = match config.light_sink {
+            LightSinkKind::Ws2812 => Vec::new(),
+            _ => pins.into_iter().flatten().collect(),
+        };
+
+        for (i, (channel, pin)) in pins.iter().enumerate() {
+            // the PWM sink's pins are its chip's channels, which aren't limited to the GPIO pins
+            anyhow::ensure!(
+                config.light_sink != LightSinkKind::Blaster || *pin <= MAX_GPIO_PIN,
+                "{channel} pin {pin} isn't a GPIO pin. The pins are numbered 0-{MAX_GPIO_PIN} by their BCM numbers"
+            );
+
+            if let Some((other, _)) = pins[..i].iter().find(|(_, other_pin)| other_pin == pin) {
+                anyhow::bail!("{channel} pin {pin} is the same as the {other} pin");
+            }
+        }
+
+        anyhow::ensure!(config.pwm_period > 0, "PWM period can't be zero");
+
+        anyhow::ensure!(
+            config.pin_w.is_some() || !config.invert_channels.contains(&Channel::White),
+            "can't invert the white channel without a white pin"
+        );
+
+        anyhow::ensure!(
+            config.connection_watchdog > 0.,
+            "connection watchdog {} must be positive",
+            config.connection_watchdog
+        );
+
+        if config.light_sink == LightSinkKind::Ws2812 {
+            anyhow::ensure!(
+                config.ws2812_leds.is_some_and(|leds| leds > 0),
+                "The WS2812 sink needs the number of LEDs on the strip"
+            );
+            anyhow::ensure!(
+                config.pin_w.is_none(),
+                "WS2812 strips don't have a separate white channel"
+            );
+            anyhow::ensure!(
+                !config.invert_pwm && config.invert_channels.is_empty(),
+                "WS2812 strips aren't driven with PWM, so it can't be inverted"
+            );
+        }
+
+        for (what, id) in [
+            ("discovery node ID", &config.discovery_node_id),
+            ("discovery object ID", &config.discovery_object_id),
+        ] {
+            if let Some(id) = id {
+                anyhow::ensure!(
+                    is_discovery_topic_level(id),
+                    "{what} '{id}' can only contain letters, numbers, underscores and hyphens"
+                );
+            }
+        }
+
+        if let Some(budget) = config.power_budget {
+            anyhow::ensure!(budget > 0., "power budget {budget} has to be larger than 0");
+        }
+
+        if let Some(template) = &config.state_template {
+            template.validate(&State::default().to_published_value(&config))?;
+        }
+
+        anyhow::ensure!(
+            (1.0..=3.0).contains(&config.gamma),
+            "gamma {} is outside 1-3",
+            config.gamma
+        );
+
+        for (channel, scale) in [
+            ("red", config.scale_r),
+            ("green", config.scale_g),
+            ("blue", config.scale_b),
+        ] {
+            anyhow::ensure!((0.0..=1.0).contains(&scale), "{channel} scale {scale} is outside 0-1");
+        }
+
+        if let Some(knee) = config.brightness_knee {
+            anyhow::ensure!(knee > 0., "brightness knee {knee} has to be larger than 0");
+        }
+
+        if let Some(curve) = &config.brightness_curve {
+            anyhow::ensure!(curve.len() >= 2, "brightness curve needs at least two points");
+            anyhow::ensure!(
+                curve.first() == Some(&0.) && curve.last() == Some(&1.),
+                "brightness curve has to start at 0 and end at 1"
+            );
+            anyhow::ensure!(
+                curve.windows(2).all(|pair| pair[0] <= pair[1]),
+                "brightness curve has to be monotonically increasing"
+            );
+        }
+
+        if let Some(brightness) = config.min_brightness {
+            anyhow::ensure!(
+                (0.0..1.0).contains(&brightness),
+                "minimum brightness {brightness} is outside 0-1"
+            );
+        }
+
+        if let Some(saturation) = config.min_saturation {
+            anyhow::ensure!(
+                (0.0..=100.0).contains(&saturation),
+                "minimum saturation {saturation} is outside 0-100"
+            );
+        }
+
+        if let Some(hue) = config.rainbow_start_hue {
+            anyhow::ensure!((0.0..=360.0).contains(&hue), "rainbow start hue {hue} is outside 0-360");
+        }
+
+        anyhow::ensure!(
+            config.pulse_rise > 0. && config.pulse_rise < 1.,
+            "pulse rise fraction {} has to be between 0 and 1 (exclusive)",
+            config.pulse_rise
+        );
+
+        if let Some(hold) = config.manual_hold {
+            anyhow::ensure!(hold >= 0., "manual hold {hold} can't be negative");
+        }
+
+        anyhow::ensure!(
+            config.sunrise_duration >= 0.,
+            "sunrise duration {} can't be negative",
+            config.sunrise_duration
+        );
+
+        anyhow::ensure!(
+            config.night_start.is_some() == config.night_end.is_some(),
+            "the night needs both a start and an end time"
+        );
+
+        if let Some(interval) = config.refresh_interval {
+            anyhow::ensure!(interval > 0., "refresh interval {interval} must be positive");
+        }
+
+        if let Some(interval) = config.state_publish_interval {
+            anyhow::ensure!(interval >= 0., "state publish interval {interval} can't be negative");
+        }
+
+        anyhow::ensure!(
+            config.transition_duration >= 0.,
+            "transition duration {} can't be negative",
+            config.transition_duration
+        );
+
+        anyhow::ensure!(
+            config.min_transition_duration >= 0.,
+            "minimum transition duration {} can't be negative",
+            config.min_transition_duration
+        );
+
+        if let Some(max) = config.max_transition_duration {
+            anyhow::ensure!(
+                max >= config.min_transition_duration,
+                "maximum transition duration {max} can't be less than the minimum {}",
+                config.min_transition_duration
+            );
+        }
+
+        if let Some(duration) = config.off_transition_duration {
+            anyhow::ensure!(duration >= 0., "off transition duration {duration} can't be negative");
+        }
+
+        anyhow::ensure!(
+            config.priority_hold >= 0.,
+            "priority hold {} can't be negative",
+            config.priority_hold
+        );
+
+        anyhow::ensure!(
+            config.state_file_timeout >= 0.,
+            "state file timeout {} can't be negative",
+            config.state_file_timeout
+        );
+
+        anyhow::ensure!(
+            config.presence_debounce >= 0.,
+            "presence debounce {} can't be negative",
+            config.presence_debounce
+        );
+
         Ok(config)
     }
 
+    /// A config with the required settings filled in, overridden by the given settings without their prefix
+    #[cfg(test)]
+    pub fn for_tests(settings: &[(&str, &str)]) -> Self {
+        let required = [
+            ("NAME", "test"),
+            ("BROKER_USERNAME", "user"),
+            ("BROKER_PASSWORD", "password"),
+            ("BROKER_URL", "mqtt://localhost:1883?client_id=test"),
+            ("PIN_R", "17"),
+            ("PIN_G", "22"),
+            ("PIN_B", "24"),
+            ("DRY_RUN", "true"),
+        ];
+        let vars = required
+            .iter()
+            .chain(settings)
+            .map(|(key, value)| (format!("{ENV_PREFIX}{key}"), value.to_string()))
+            .collect();
+
+        Self::from_vars(vars).expect("invalid test config")
+    }
+
+    /// Whether a channel's duty cycle is inverted, either on its own or along with all the others
+    pub fn is_inverted(&self, channel: Channel) -> bool {
+        self.invert_pwm || self.invert_channels.contains(&channel)
+    }
+
     pub fn own_topic(&self) -> String {
         format!("{}/{}", self.mqtt_topic, self.name)
     }
@@ -44,43 +742,693 @@ impl Config {
         format!("{}/set", self.own_topic())
     }
 
+    pub fn brightness_command_topic(&self) -> String {
+        format!("{}/brightness/set", self.own_topic())
+    }
+
     pub fn state_topic(&self) -> String {
         format!("{}/state", self.own_topic())
     }
 
+    pub fn capabilities_topic(&self) -> String {
+        format!("{}/capabilities", self.own_topic())
+    }
+
+    pub fn templated_state_topic(&self) -> String {
+        format!("{}/state/templated", self.own_topic())
+    }
+
+    /// The topic of the light's availability. Lights sharing a connection share their availability, since the
+    /// connection's last will can only be in one topic
+    pub fn availability_topic(&self) -> String {
+        if !self.lights.is_empty() {
+            return format!("{}/availability", self.mqtt_topic);
+        }
+
+        format!("{}/availability", self.own_topic())
+    }
+
+    pub fn hex_topic(&self) -> String {
+        format!("{}/hex", self.own_topic())
+    }
+
     pub fn unique_id(&self) -> String {
         format!("moodlight_{}", self.name.to_ascii_lowercase().replace(' ', "_"))
     }
 
+    pub fn mode_label(&self, mode: Mode) -> &str {
+        self.mode_labels
+            .iter()
+            .find_map(|(m, label)| (*m == mode).then_some(label.as_str()))
+            .unwrap_or(mode.name())
+    }
+
+    /// The mode with the given label or name
+    pub fn mode_for_label(&self, label: &str) -> Option<Mode> {
+        Mode::ALL
+            .into_iter()
+            .find(|mode| self.mode_label(*mode) == label || mode.name() == label)
+    }
+
     pub fn home_assistant_light_topic(&self) -> String {
-        format!("{}/light/{}/config", self.home_assistant_topic, self.unique_id())
+        self.home_assistant_discovery_topic("light")
     }
 
     pub fn home_assistant_select_topic(&self) -> String {
-        format!("{}/select/{}/config", self.home_assistant_topic, self.unique_id())
+        self.home_assistant_discovery_topic("select")
     }
 
     pub fn home_assistant_number_topic(&self) -> String {
-        format!("{}/number/{}/config", self.home_assistant_topic, self.unique_id())
+        self.home_assistant_discovery_topic("number")
     }
+
+    fn home_assistant_discovery_topic(&self, component: &str) -> String {
+        let object_id = self.discovery_object_id.clone().unwrap_or_else(|| self.unique_id());
+
+        match &self.discovery_node_id {
+            Some(node_id) => format!("{}/{component}/{node_id}/{object_id}/config", self.home_assistant_topic),
+            None => format!("{}/{component}/{object_id}/config", self.home_assistant_topic),
+        }
+    }
+}
+
+/// Implements reloading the config with every setting listed as either one that takes effect while running, or one
+/// that needs a restart. Destructuring the whole config means a new setting doesn't compile until it's listed in
+/// either
+macro_rules! impl_reload {
+    (live: [$($live:ident),* $(,)?], restart: [$($restart:ident),* $(,)?] $(,)?) => {
+        impl Config {
+            /// Takes the settings of a reloaded config that can be changed while running. The rest are kept as they
+            /// are, since they're only used when starting, like the connection, the subscriptions and the discovery
+            pub fn reload(&mut self, new: Config) -> ReloadedSettings {
+                let Config { $($live,)* $($restart,)* } = new;
+                let mut reloaded = ReloadedSettings::default();
+
+                // not all the settings can be compared, but they can all be debug formatted
+                $(
+                    if format!("{:?}", self.$live) != format!("{:?}", $live) {
+                        reloaded.applied.push(stringify!($live));
+                        self.$live = $live;
+                    }
+                )*
+                $(
+                    if format!("{:?}", self.$restart) != format!("{:?}", $restart) {
+                        reloaded.need_restart.push(stringify!($restart));
+                    }
+                )*
+
+                reloaded
+            }
+        }
+    };
+}
+
+impl_reload! {
+    live: [
+        pin_r, pin_g, pin_b, pwm_period, invert_pwm, invert_channels,
+        step_duration, rainbow_color, rainbow_start_hue, pulse_rise,
+        transition_overlap, ignore_retained_commands, strict_commands, refresh_interval, transition_duration,
+        transition_easing, transition_brightness, transition_color, color_interpolation,
+        scale_transitions, min_transition_duration, max_transition_duration,
+        off_transition_duration, brightness_implies_on, priority_hold, past_schedule, power_budget, gamma, scale_r,
+        scale_g, scale_b, palette, brightness_knee, brightness_curve, min_brightness, min_saturation, publish_hex,
+        state_template, change_log, change_log_max_size, state_file, panic_color, manual_hold, on_time, off_time,
+        sunrise_time, sunrise_duration, night_start, night_end,
+        night_brightness, presence_empty_action, presence_dim_brightness, presence_debounce, state_qos,
+        state_retain, state_publish_interval, clear_discovery_on_shutdown,
+    ],
+    restart: [
+        name, lights, mqtt_topic, broker_username, broker_password, broker_url, broker_tls, broker_ca, connection_watchdog,
+        light_sink,
+        dry_run, blaster, pwm_chip, ws2812_device, ws2812_leds, pin_w, no_local, udp_sink, metrics_port, api_port,
+        legacy_brightness_topic, state_file_timeout, all_off_topic, presence_topic, mode_labels, home_assistant_discovery,
+        home_assistant_topic,
+        suggested_area, device_manufacturer, device_model, configuration_url, discovery_qos, discovery_retain, availability_qos, availability_retain, availability_mode, discovery_node_id,
+        discovery_object_id,
+    ],
+}
+
+/// The config as environment variables, with the config file's settings as the variables they'd be, overridden by the
+/// actual environment variables. The config file is optional if its path isn't given. The broker's and the hardware's
+/// settings the file sets can't be overridden, nor the ones the file lists in its `file_only` setting
+fn config_vars() -> anyhow::Result<(HashMap<String, String>, Profiles)> {
+    let (path, explicit) = match env::var(CONFIG_FILE_VAR) {
+        Ok(path) => (PathBuf::from(path), true),
+        Err(_) => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+    };
+
+    let (vars, profiles) = match fs::read_to_string(&path) {
+        Ok(contents) => {
+            info!("Reading config file {}", path.display());
+            parse_config_file(&contents).map_err(|e| anyhow::anyhow!("invalid config file {}: {e}", path.display()))?
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => Default::default(),
+        Err(e) => return Err(anyhow::anyhow!("failed to read config file {}: {e}", path.display())),
+    };
+
+    Ok((with_env_overrides(vars, env::vars())?, profiles))
+}
+
+/// Overrides the config file's variables with the environment's, unless they're protected
+fn with_env_overrides(
+    mut vars: HashMap<String, String>,
+    env_vars: impl IntoIterator<Item = (String, String)>,
+) -> anyhow::Result<HashMap<String, String>> {
+    let listed = vars.remove(FILE_ONLY_SETTING).unwrap_or_default();
+    let file_only: Vec<String> = FILE_ONLY_SETTINGS
+        .iter()
+        .map(|setting| setting.to_string())
+        .chain(listed.split(',').map(|setting| setting.trim().to_ascii_uppercase()))
+        .filter(|setting| !setting.is_empty())
+        .collect();
+
+    for (name, value) in env_vars {
+        // a light's own setting is prefixed with its name, so it ends in the setting's name. it overrides the setting
+        // the lights share, so it's protected by the file setting either of them
+        let protected = name.strip_prefix(ENV_PREFIX).and_then(|setting| {
+            file_only
+                .iter()
+                .find(|protected| setting == protected.as_str() || setting.ends_with(&format!("_{protected}")))
+        });
+        let set_in_file = protected.is_some_and(|protected| {
+            vars.contains_key(&name) || vars.contains_key(&format!("{ENV_PREFIX}{protected}"))
+        });
+
+        anyhow::ensure!(
+            !set_in_file,
+            "{name} is set in the config file and can't be overridden by the environment"
+        );
+
+        vars.insert(name, value);
+    }
+
+    Ok(vars)
+}
+
+/// Turns the settings in a TOML config file into environment variables. The keys are the variables' names without
+/// the prefix, and the values are given like in the variables, except that lists can be given as arrays. A table holds
+/// the settings of the light it's named after, except for the `profile` table which holds the named profiles
+fn parse_config_file(contents: &str) -> anyhow::Result<(HashMap<String, String>, Profiles)> {
+    fn to_var(value: &toml::Value) -> Option<String> {
+        match value {
+            toml::Value::String(value) => Some(value.clone()),
+            toml::Value::Integer(value) => Some(value.to_string()),
+            toml::Value::Float(value) => Some(value.to_string()),
+            toml::Value::Boolean(value) => Some(value.to_string()),
+            toml::Value::Datetime(value) => Some(value.to_string()),
+            toml::Value::Array(_) | toml::Value::Table(_) => None,
+        }
+    }
+
+    fn setting_to_var(key: &str, value: &toml::Value) -> anyhow::Result<String> {
+        match value {
+            toml::Value::Array(values) => values
+                .iter()
+                .map(to_var)
+                .collect::<Option<Vec<_>>>()
+                .map(|v| v.join(",")),
+            value => to_var(value),
+        }
+        .ok_or_else(|| anyhow::anyhow!("setting '{key}' can only be a single value or an array of them"))
+    }
+
+    let table: toml::Table = contents.parse()?;
+    let mut vars = HashMap::new();
+    let mut profiles = Profiles::new();
+
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(table) if key == PROFILES_TABLE => {
+                for (name, settings) in table {
+                    let toml::Value::Table(settings) = settings else {
+                        anyhow::bail!("profile '{name}' isn't a table of settings");
+                    };
+
+                    let settings = settings
+                        .iter()
+                        .map(|(setting, value)| {
+                            let var = setting_to_var(&format!("{key}.{name}.{setting}"), value)?;
+                            Ok((setting.to_ascii_uppercase(), var))
+                        })
+                        .collect::<anyhow::Result<_>>()?;
+                    profiles.insert(name, settings);
+                }
+            }
+            toml::Value::Table(settings) => {
+                for (setting, value) in settings {
+                    let var = setting_to_var(&format!("{key}.{setting}"), &value)?;
+                    let name = format!("{ENV_PREFIX}{}_{}", light_var_name(&key), setting.to_ascii_uppercase());
+                    vars.insert(name, var);
+                }
+            }
+            value => {
+                let var = setting_to_var(&key, &value)?;
+                vars.insert(format!("{ENV_PREFIX}{}", key.to_ascii_uppercase()), var);
+            }
+        }
+    }
+
+    Ok((vars, profiles))
+}
+
+/// Resolves the selected profile into the settings it stands for. A profile has the light's pins, `pins`, in the order
+/// of its channels, `color_order` like `grb` or `rgbw`, defaulting to red, green, blue and white. Along with them it
+/// can have the gamma and the channels' calibration, `gamma` and `scale_r`, `scale_g` and `scale_b`. Setting any of
+/// them directly as well is ambiguous and an error
+fn with_profile(mut vars: HashMap<String, String>, profiles: &Profiles) -> anyhow::Result<HashMap<String, String>> {
+    let Some(name) = vars.remove(PROFILE_VAR) else {
+        return Ok(vars);
+    };
+    let profile = profiles
+        .get(&name)
+        .ok_or_else(|| anyhow::anyhow!("there's no profile '{name}' in the config file"))?;
+
+    let mut settings = Vec::new();
+
+    for (setting, value) in profile {
+        match setting.as_str() {
+            "PINS" => {
+                let pins: Vec<&str> = value.split(',').map(str::trim).collect();
+                let order = match profile.get("COLOR_ORDER") {
+                    Some(order) => order.to_ascii_uppercase(),
+                    None => "RGBW".chars().take(pins.len()).collect(),
+                };
+
+                // each of the channels once, red, green and blue always and white only if the light has it
+                let mut channels: Vec<char> = order.chars().collect();
+                channels.sort_unstable();
+                channels.dedup();
+                anyhow::ensure!(
+                    order.len() == pins.len()
+                        && channels.len() == order.len()
+                        && ['B', 'G', 'R'].iter().all(|c| channels.contains(c))
+                        && channels.iter().all(|c| "RGBW".contains(*c)),
+                    "profile '{name}' has {} pins for the colour order '{order}'",
+                    pins.len()
+                );
+
+                for (channel, pin) in order.chars().zip(pins) {
+                    settings.push((format!("PIN_{channel}"), pin.to_string()));
+                }
+            }
+            "COLOR_ORDER" => anyhow::ensure!(
+                profile.contains_key("PINS"),
+                "profile '{name}' has a colour order without any pins"
+            ),
+            "GAMMA" | "SCALE_R" | "SCALE_G" | "SCALE_B" => settings.push((setting.clone(), value.clone())),
+            _ => anyhow::bail!(
+                "profile '{name}' has an unknown setting '{}'",
+                setting.to_ascii_lowercase()
+            ),
+        }
+    }
+
+    for (setting, value) in settings {
+        let var = format!("{ENV_PREFIX}{setting}");
+        anyhow::ensure!(
+            !vars.contains_key(&var),
+            "{var} is set both directly and by profile '{name}'"
+        );
+        vars.insert(var, value);
+    }
+
+    Ok(vars)
+}
+
+/// The light's name as it's used in the prefix of its own settings
+fn light_var_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Whether one topic is the same as the other or nested under it, comparing whole topic levels
+fn topic_namespaces_overlap(a: &str, b: &str) -> bool {
+    let a = a.trim_end_matches('/').split('/');
+    let b = b.trim_end_matches('/').split('/');
+
+    a.zip(b).all(|(a, b)| a == b)
+}
+
+/// Whether the ID is a valid level in a discovery topic. Home Assistant only accepts a limited set of characters in
+/// them, which also leaves out everything MQTT would treat specially
+fn is_discovery_topic_level(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
 }
 
 fn default_mqtt_topic() -> String {
     String::from(DEFAULT_MQTT_TOPIC)
 }
 
-fn default_mqtt_port() -> u16 {
-    1883
+fn default_connection_watchdog() -> f32 {
+    30.
+}
+
+fn default_light_sink() -> LightSinkKind {
+    LightSinkKind::Blaster
 }
 
 fn default_blaster() -> PathBuf {
     PathBuf::from("/dev/pi-blaster")
 }
 
+fn default_pwm_chip() -> PathBuf {
+    PathBuf::from("/sys/class/pwm/pwmchip0")
+}
+
+fn default_pwm_period() -> u32 {
+    // 1 kHz, fast enough not to flicker
+    1_000_000
+}
+
+fn default_ws2812_device() -> PathBuf {
+    PathBuf::from("/dev/spidev0.0")
+}
+
 fn default_step_duration() -> f32 {
     0.02
 }
 
+fn default_gamma() -> f32 {
+    1.
+}
+
+fn default_channel_scale() -> f32 {
+    1.
+}
+
+fn default_home_assistant_discovery() -> bool {
+    true
+}
+
 fn default_home_assistant_topic() -> String {
     String::from(DEFAULT_HOME_ASSISTANT_MQTT_TOPIC)
 }
+
+fn default_discovery_qos() -> QoS {
+    QoS::AtLeastOnce
+}
+
+fn default_retain() -> bool {
+    true
+}
+
+fn default_state_qos() -> QoS {
+    QoS::AtLeastOnce
+}
+
+fn default_availability_qos() -> QoS {
+    QoS::AtLeastOnce
+}
+
+fn default_rainbow_color_policy() -> RainbowColorPolicy {
+    RainbowColorPolicy::Ignore
+}
+
+fn default_pulse_rise() -> f32 {
+    0.2
+}
+
+fn default_transition_overlap() -> TransitionOverlap {
+    TransitionOverlap::Cancel
+}
+
+fn default_no_local() -> bool {
+    true
+}
+
+fn default_transition_duration() -> f32 {
+    0.5
+}
+
+fn default_transition_brightness() -> bool {
+    true
+}
+
+fn default_transition_color() -> bool {
+    true
+}
+
+fn default_brightness_implies_on() -> bool {
+    true
+}
+
+fn default_priority_hold() -> f32 {
+    300.0
+}
+
+fn default_past_schedule_policy() -> PastSchedulePolicy {
+    PastSchedulePolicy::Fire
+}
+
+fn default_change_log_max_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_state_file_timeout() -> f32 {
+    5.
+}
+
+fn default_sunrise_duration() -> f32 {
+    30. * 60.
+}
+
+fn default_night_brightness() -> u8 {
+    // about 10%
+    25
+}
+
+fn default_presence_empty_action() -> PresenceAction {
+    PresenceAction::Off
+}
+
+fn default_presence_dim_brightness() -> u8 {
+    25
+}
+
+fn default_presence_debounce() -> f32 {
+    30.0
+}
+
+fn deserialize_mode_labels<'de, D>(deserializer: D) -> Result<Vec<(Mode, String)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    let mut labels: Vec<(Mode, String)> = Vec::new();
+
+    for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let (mode, label) = pair
+            .split_once(':')
+            .ok_or_else(|| serde::de::Error::custom(format!("mode label '{pair}' isn't in the form Mode:Label")))?;
+
+        let mode = Mode::deserialize(mode.trim().into_deserializer())?;
+        let label = label.trim().to_string();
+
+        // the labels are mapped back to modes when Home Assistant sends them, so they have to be unambiguous
+        if label.is_empty() || labels.iter().any(|(m, l)| *m == mode || *l == label) {
+            return Err(serde::de::Error::custom(format!(
+                "mode label '{pair}' is empty or duplicates another label"
+            )));
+        }
+
+        labels.push((mode, label));
+    }
+
+    Ok(labels)
+}
+
+fn deserialize_qos<'de, D>(deserializer: D) -> Result<QoS, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = u8::deserialize(deserializer)?;
+    mqttbytes::qos(value).ok_or_else(|| serde::de::Error::custom(format!("QoS level {value} isn't 0, 1 or 2")))
+}
+
+fn deserialize_optional_color<'de, D>(deserializer: D) -> Result<Option<Color>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_color(&value).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_time<'de, D>(deserializer: D) -> Result<Option<NaiveTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(value.trim(), "%H:%M")
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("time '{value}' isn't in the form HH:MM: {e}")))
+}
+
+fn deserialize_palette<'de, D>(deserializer: D) -> Result<Vec<Color>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    value
+        .split(';')
+        .filter(|color| !color.trim().is_empty())
+        .map(|color| parse_color(color).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Parses a colour in the form hue,saturation
+fn parse_color(value: &str) -> Result<Color, String> {
+    let (h, s) = value
+        .split_once(',')
+        .ok_or_else(|| format!("colour '{value}' isn't in the form hue,saturation"))?;
+
+    let parse = |component: &str, max: f32| {
+        component
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|c| (0.0..=max).contains(c))
+            .ok_or_else(|| format!("invalid colour component '{component}' in '{value}'"))
+    };
+
+    Ok(Color {
+        h: parse(h, 360.)?,
+        s: parse(s, 100.)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_overrides_unprotected_settings() {
+        let (file, _) = parse_config_file("broker_url = \"mqtt://file\"\ngamma = 2.2").expect("invalid config file");
+        let merged = with_env_overrides(file, vars(&[("MOODLIGHT_GAMMA", "1.8"), ("MOODLIGHT_PIN_R", "5")]))
+            .expect("override rejected");
+
+        assert_eq!(merged["MOODLIGHT_GAMMA"], "1.8");
+        assert_eq!(merged["MOODLIGHT_BROKER_URL"], "mqtt://file");
+        // a protected setting the file doesn't set can still come from the environment
+        assert_eq!(merged["MOODLIGHT_PIN_R"], "5");
+
+        // and so can another light's own setting
+        let (file, _) = parse_config_file("[desk]\npin_r = 17").expect("invalid config file");
+        let merged = with_env_overrides(file, vars(&[("MOODLIGHT_SHELF_PIN_R", "5")])).expect("override rejected");
+        assert_eq!(merged["MOODLIGHT_SHELF_PIN_R"], "5");
+    }
+
+    #[test]
+    fn env_cant_override_protected_settings() {
+        for (file, env) in [
+            ("broker_url = \"mqtt://file\"", "MOODLIGHT_BROKER_URL"),
+            ("[desk]\npin_r = 17", "MOODLIGHT_DESK_PIN_R"),
+            // a light's own setting overrides the shared one
+            ("broker_url = \"mqtt://file\"", "MOODLIGHT_DESK_BROKER_URL"),
+            ("pin_r = 17", "MOODLIGHT_DESK_PIN_R"),
+            ("file_only = [\"gamma\"]\ngamma = 2.2", "MOODLIGHT_GAMMA"),
+        ] {
+            let (file, _) = parse_config_file(file).expect("invalid config file");
+            assert!(with_env_overrides(file, vars(&[(env, "1")])).is_err(), "{env}");
+        }
+    }
+
+    const PROFILES: &str = "[profile.breadboard]\npins = [17, 22, 24]\ncolor_order = \"grb\"\ngamma = 2.2\nscale_g = \
+                            0.8\n\n[profile.strip]\npins = [5, 6, 13, 19]";
+
+    #[test]
+    fn profile_resolves_into_settings() {
+        let (_, profiles) = parse_config_file(PROFILES).expect("invalid config file");
+
+        let resolved = with_profile(vars(&[(PROFILE_VAR, "breadboard")]), &profiles).expect("profile rejected");
+        assert_eq!(
+            resolved,
+            vars(&[
+                ("MOODLIGHT_PIN_G", "17"),
+                ("MOODLIGHT_PIN_R", "22"),
+                ("MOODLIGHT_PIN_B", "24"),
+                ("MOODLIGHT_GAMMA", "2.2"),
+                ("MOODLIGHT_SCALE_G", "0.8"),
+            ])
+        );
+
+        // without a colour order, the pins are red, green, blue and white
+        let resolved = with_profile(vars(&[(PROFILE_VAR, "strip")]), &profiles).expect("profile rejected");
+        assert_eq!(resolved["MOODLIGHT_PIN_R"], "5");
+        assert_eq!(resolved["MOODLIGHT_PIN_W"], "19");
+
+        let light = vars(&[
+            ("MOODLIGHT_NAME", "test"),
+            ("MOODLIGHT_BROKER_USERNAME", "user"),
+            ("MOODLIGHT_BROKER_PASSWORD", "password"),
+            ("MOODLIGHT_BROKER_URL", "mqtt://localhost:1883?client_id=test"),
+            (PROFILE_VAR, "breadboard"),
+        ]);
+        let config = with_profile(light, &profiles)
+            .and_then(Config::from_vars)
+            .expect("invalid config");
+        assert_eq!((config.pin_r, config.pin_g, config.pin_b), (22, 17, 24));
+        assert_eq!(config.gamma, 2.2);
+    }
+
+    #[test]
+    fn profile_rejects_ambiguous_or_unknown_settings() {
+        let (_, profiles) = parse_config_file(PROFILES).expect("invalid config file");
+
+        for vars in [
+            vars(&[(PROFILE_VAR, "breadboard"), ("MOODLIGHT_PIN_R", "4")]),
+            vars(&[(PROFILE_VAR, "breadboard"), ("MOODLIGHT_GAMMA", "1.8")]),
+            vars(&[(PROFILE_VAR, "missing")]),
+        ] {
+            assert!(with_profile(vars.clone(), &profiles).is_err(), "{vars:?}");
+        }
+
+        for profile in [
+            "[profile.bad]\npins = [1, 2]",
+            "[profile.bad]\npins = [1, 2, 3]\ncolor_order = \"rrb\"",
+            "[profile.bad]\ncolor_order = \"rgb\"",
+            "[profile.bad]\nbrightness = 10",
+        ] {
+            let (_, profiles) = parse_config_file(profile).expect("invalid config file");
+            assert!(
+                with_profile(vars(&[(PROFILE_VAR, "bad")]), &profiles).is_err(),
+                "{profile}"
+            );
+        }
+    }
+
+    #[test]
+    fn topic_namespaces() {
+        for (a, b) in [
+            ("lights/desk", "lights/desk"),
+            ("lights", "lights/desk"),
+            ("lights/desk/", "lights/desk"),
+            ("lights/desk/state", "lights/desk"),
+        ] {
+            assert!(topic_namespaces_overlap(a, b), "{a} {b}");
+            assert!(topic_namespaces_overlap(b, a), "{b} {a}");
+        }
+
+        // only whole levels collide
+        for (a, b) in [
+            ("lights/desk", "lights/desk2"),
+            ("lights/desk", "lights/shelf"),
+            ("light", "lights"),
+        ] {
+            assert!(!topic_namespaces_overlap(a, b), "{a} {b}");
+            assert!(!topic_namespaces_overlap(b, a), "{b} {a}");
+        }
+    }
+}
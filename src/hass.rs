@@ -1,11 +1,26 @@
 use serde::Serialize;
+use serde_json::{Map, Value};
 
-use crate::{config::Config, state::MAX_RAINBOW_SPEED_SETTING};
+use crate::{
+    capabilities::COLOR_MODES,
+    config::{AvailabilityMode, Config},
+    state::{Mode, FLASH_LONG_S, FLASH_SHORT_S, MAX_MIREDS, MAX_RAINBOW_SPEED_SETTING, MIN_MIREDS},
+    VERSION,
+};
 
 #[derive(Debug, Serialize)]
 struct HomeAssistantDevice {
     name: String,
     identifiers: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggested_area: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    sw_version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    configuration_url: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -14,12 +29,21 @@ pub struct HomeAssistantLightConfig {
     unique_id: String,
     command_topic: String,
     state_topic: String,
+    availability_topic: String,
+    availability_mode: AvailabilityMode,
     device: HomeAssistantDevice,
 
     schema: &'static str,
     color_mode: bool,
     brightness: bool,
     supported_color_modes: &'static [&'static str],
+    min_mireds: u16,
+    max_mireds: u16,
+    effect: bool,
+    effect_list: Vec<String>,
+    flash: bool,
+    flash_time_short: u32,
+    flash_time_long: u32,
 }
 
 #[derive(Debug, Serialize)]
@@ -28,11 +52,13 @@ pub struct HomeAssistantSelectConfig {
     unique_id: String,
     command_topic: String,
     state_topic: String,
+    availability_topic: String,
+    availability_mode: AvailabilityMode,
     device: HomeAssistantDevice,
 
-    options: &'static [&'static str],
-    command_template: &'static str,
-    value_template: &'static str,
+    options: Vec<String>,
+    command_template: String,
+    value_template: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -41,6 +67,8 @@ pub struct HomeAssistantNumberConfig {
     unique_id: String,
     command_topic: String,
     state_topic: String,
+    availability_topic: String,
+    availability_mode: AvailabilityMode,
     device: HomeAssistantDevice,
 
     min: f32,
@@ -50,6 +78,20 @@ pub struct HomeAssistantNumberConfig {
     value_template: &'static str,
 }
 
+impl HomeAssistantDevice {
+    fn new(config: &Config, unique_id: String) -> Self {
+        Self {
+            name: format!("{} moodlight", config.name),
+            identifiers: unique_id,
+            suggested_area: config.suggested_area.clone(),
+            manufacturer: config.device_manufacturer.clone(),
+            model: config.device_model.clone(),
+            sw_version: VERSION,
+            configuration_url: config.configuration_url.clone(),
+        }
+    }
+}
+
 impl HomeAssistantLightConfig {
     pub fn new(config: &Config) -> Self {
         let unique_id = config.unique_id();
@@ -59,15 +101,22 @@ impl HomeAssistantLightConfig {
             unique_id: format!("{}_light", unique_id),
             command_topic: config.command_topic(),
             state_topic: config.state_topic(),
-            device: HomeAssistantDevice {
-                name: format!("{} moodlight", config.name),
-                identifiers: unique_id,
-            },
+            availability_topic: config.availability_topic(),
+            availability_mode: config.availability_mode,
+            device: HomeAssistantDevice::new(config, unique_id),
 
             schema: "json",
             color_mode: true,
             brightness: true,
-            supported_color_modes: &["hs"],
+            supported_color_modes: COLOR_MODES,
+            min_mireds: MIN_MIREDS,
+            max_mireds: MAX_MIREDS,
+            // the modes are the light's effects, labelled like in the mode select
+            effect: true,
+            effect_list: Mode::ALL.iter().map(|m| config.mode_label(*m).to_string()).collect(),
+            flash: true,
+            flash_time_short: FLASH_SHORT_S,
+            flash_time_long: FLASH_LONG_S,
         }
     }
 }
@@ -76,19 +125,36 @@ impl HomeAssistantSelectConfig {
     pub fn new(config: &Config) -> Self {
         let unique_id = config.unique_id();
 
+        let options: Vec<String> = Mode::ALL.iter().map(|m| config.mode_label(*m).to_string()).collect();
+        let modes: Map<String, Value> = Mode::ALL
+            .iter()
+            .map(|m| (config.mode_label(*m).to_string(), m.name().into()))
+            .collect();
+        let labels: Map<String, Value> = Mode::ALL
+            .iter()
+            .map(|m| (m.name().to_string(), config.mode_label(*m).into()))
+            .collect();
+
         Self {
             name: "Mode",
             unique_id: format!("{}_mode_select", unique_id),
             command_topic: config.command_topic(),
             state_topic: config.state_topic(),
-            device: HomeAssistantDevice {
-                name: format!("{} moodlight", config.name),
-                identifiers: unique_id,
-            },
-
-            options: &["Static", "Rainbow"],
-            command_template: "{\"mode\": \"{{ value }}\"}",
-            value_template: "{{ value_json.mode }}",
+            availability_topic: config.availability_topic(),
+            availability_mode: config.availability_mode,
+            device: HomeAssistantDevice::new(config, unique_id),
+
+            options,
+            // the displayed labels may differ from the modes' names, so map between them in the templates. the maps
+            // are serialised as JSON objects which are valid Jinja dict literals
+            command_template: format!(
+                "{{% set modes = {} %}}{{\"mode\": \"{{{{ modes[value] }}}}\"}}",
+                Value::Object(modes)
+            ),
+            value_template: format!(
+                "{{% set labels = {} %}}{{{{ labels[value_json.mode] }}}}",
+                Value::Object(labels)
+            ),
         }
     }
 }
@@ -102,10 +168,9 @@ impl HomeAssistantNumberConfig {
             unique_id: format!("{}_rainbow_speed", unique_id),
             command_topic: config.command_topic(),
             state_topic: config.state_topic(),
-            device: HomeAssistantDevice {
-                name: format!("{} moodlight", config.name),
-                identifiers: unique_id,
-            },
+            availability_topic: config.availability_topic(),
+            availability_mode: config.availability_mode,
+            device: HomeAssistantDevice::new(config, unique_id),
 
             min: 0.,
             max: MAX_RAINBOW_SPEED_SETTING,
@@ -115,3 +180,92 @@ impl HomeAssistantNumberConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config() -> Config {
+        Config::for_tests(&[
+            ("NAME", "desk"),
+            ("MQTT_TOPIC", "lights"),
+            ("MODE_LABELS", "Rainbow:Colour loop"),
+        ])
+    }
+
+    fn serialized(config: impl Serialize) -> Value {
+        serde_json::to_value(config).expect("failed to serialise discovery config")
+    }
+
+    fn assert_topics(value: &Value) {
+        assert_eq!(value["command_topic"], "lights/desk/set");
+        assert_eq!(value["state_topic"], "lights/desk/state");
+        assert_eq!(value["availability_topic"], "lights/desk/availability");
+        assert_eq!(value["availability_mode"], "latest");
+        assert_eq!(value["device"]["identifiers"], "moodlight_desk");
+        assert_eq!(value["device"]["sw_version"], VERSION);
+    }
+
+    #[test]
+    fn light_config() {
+        let value = serialized(HomeAssistantLightConfig::new(&config()));
+
+        assert_topics(&value);
+        assert_eq!(value["unique_id"], "moodlight_desk_light");
+        assert_eq!(value["name"], Value::Null);
+        assert_eq!(value["schema"], "json");
+        assert_eq!(value["supported_color_modes"], json!(["hs", "color_temp"]));
+        assert_eq!(value["effect_list"][1], "Colour loop");
+    }
+
+    #[test]
+    fn select_config() {
+        let value = serialized(HomeAssistantSelectConfig::new(&config()));
+
+        assert_topics(&value);
+        assert_eq!(value["unique_id"], "moodlight_desk_mode_select");
+        assert_eq!(value["options"][0], "Static");
+        assert_eq!(value["options"][1], "Colour loop");
+
+        let command_template = value["command_template"].as_str().expect("no command template");
+        assert!(command_template.starts_with("{% set modes = {"));
+        assert!(command_template.contains(r#""Colour loop":"Rainbow""#));
+        assert!(command_template.ends_with(r#"%}{"mode": "{{ modes[value] }}"}"#));
+
+        let value_template = value["value_template"].as_str().expect("no value template");
+        assert!(value_template.starts_with("{% set labels = {"));
+        assert!(value_template.contains(r#""Rainbow":"Colour loop""#));
+        assert!(value_template.ends_with("%}{{ labels[value_json.mode] }}"));
+    }
+
+    #[test]
+    fn number_config() {
+        let value = serialized(HomeAssistantNumberConfig::new(&config()));
+
+        assert_topics(&value);
+        assert_eq!(value["unique_id"], "moodlight_desk_rainbow_speed");
+        assert_eq!(value["command_template"], r#"{"rainbow_speed": {{ value }}}"#);
+        assert_eq!(value["value_template"], "{{ value_json.rainbow_speed }}");
+        assert_eq!(value["max"], json!(MAX_RAINBOW_SPEED_SETTING));
+    }
+
+    #[test]
+    fn configured_availability_mode() {
+        let config = Config::for_tests(&[("AVAILABILITY_MODE", "all")]);
+
+        assert_eq!(
+            serialized(HomeAssistantLightConfig::new(&config))["availability_mode"],
+            "all"
+        );
+        assert_eq!(
+            serialized(HomeAssistantSelectConfig::new(&config))["availability_mode"],
+            "all"
+        );
+        assert_eq!(
+            serialized(HomeAssistantNumberConfig::new(&config))["availability_mode"],
+            "all"
+        );
+    }
+}
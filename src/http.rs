@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+// the servers are only meant for a handful of local clients, so requests are kept small and the clients prompt
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The parts of an HTTP request the light's servers look at
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+/// Reads a single request from a connection. Anything the servers don't need, like keep-alive or chunked bodies, isn't
+/// supported
+pub async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    time::timeout(REQUEST_TIMEOUT, read_request_inner(stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out reading request"))?
+}
+
+async fn read_request_inner(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut buf = Vec::new();
+
+    let header_end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+
+        anyhow::ensure!(buf.len() < MAX_REQUEST_SIZE, "request headers too large");
+        read_more(stream, &mut buf).await?;
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    anyhow::ensure!(!method.is_empty() && !path.is_empty(), "malformed request line");
+
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<usize>())
+        .transpose()?
+        .unwrap_or(0);
+    anyhow::ensure!(content_length <= MAX_REQUEST_SIZE, "request body too large");
+
+    let body_start = header_end + 4;
+    while buf.len() < body_start + content_length {
+        read_more(stream, &mut buf).await?;
+    }
+
+    Ok(Request {
+        method,
+        path,
+        body: buf[body_start..body_start + content_length].to_vec(),
+    })
+}
+
+async fn read_more(stream: &mut TcpStream, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+    let mut chunk = [0; 1024];
+    let read = stream.read(&mut chunk).await?;
+    anyhow::ensure!(read > 0, "connection closed mid-request");
+
+    buf.extend_from_slice(&chunk[..read]);
+    Ok(())
+}
+
+/// Writes a response and closes the connection
+pub async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
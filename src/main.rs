@@ -1,32 +1,79 @@
 // because you're an idiot and never remember it, the magic incantation so this runs on a Pi Zero W is
 // cross build --target=arm-unknown-linux-gnueabihf --release
 
+mod all_off;
+mod api;
+mod capabilities;
+mod changelog;
 mod config;
 mod hass;
+mod http;
+mod metrics;
+mod output;
+mod presence;
+mod schedule;
+mod sink;
 mod state;
+mod state_file;
+mod sunrise;
+mod template;
 
-use std::{task::Poll, time::Duration};
+use std::{collections::HashSet, io::Write, task::Poll, time::Duration};
 
+use chrono::{Local, NaiveDateTime};
 use log::*;
-use rumqttc::v5::{
-    mqttbytes::{
-        v5::{Filter, Packet, Publish},
-        QoS,
+use palette::{FromColor, Hsv, Srgb};
+use rumqttc::{
+    v5::{
+        mqttbytes::{
+            v5::{Filter, LastWill, Packet, PubAck, PubComp, Publish, SubscribeReasonCode},
+            QoS,
+        },
+        AsyncClient, Event, EventLoop, MqttOptions,
     },
-    AsyncClient, Event, EventLoop, MqttOptions,
+    Outgoing, Transport,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
     signal::unix::{signal, SignalKind},
+    sync::{broadcast, watch},
     time::{self, MissedTickBehavior},
 };
 
 use crate::{
-    config::Config,
+    all_off::AllOff,
+    capabilities::Capabilities,
+    config::{Config, LogFormat, PastSchedulePolicy, TransitionOverlap},
     hass::{HomeAssistantLightConfig, HomeAssistantNumberConfig, HomeAssistantSelectConfig},
-    state::{Mode, State},
+    output::Output,
+    presence::Presence,
+    state::{ColorInterpolation, Easing, Flash, HueRange, Mode, SaturationSweep, State},
+    sunrise::Sunrise,
 };
 
+/// The version and the commit it was built from
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("MOODLIGHT_GIT_HASH"), ")");
+
+// the range of how long to wait before polling the MQTT client again after an error. the wait doubles on every
+// consecutive error
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+// how long to wait for the broker to acknowledge the last messages when shutting down
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the payloads of the availability topic, which are Home Assistant's defaults
+const AVAILABILITY_ONLINE: &str = "online";
+const AVAILABILITY_OFFLINE: &str = "offline";
+
+// how many of the broker's events can wait for a light to get to them before the light misses some
+const EVENT_QUEUE_SIZE: usize = 64;
+
+// the source of the REST API's commands in the change log
+const API_SOURCE: &str = "api";
+
+// the command fields that don't change the light by hand
+const MOMENTARY_FIELDS: &[&str] = &["ack", "flash", "capabilities", "priority"];
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Copy, Clone)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum OnState {
@@ -34,89 +81,760 @@ pub enum OnState {
     Off,
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+#[serde(from = "ColorRepresentation")]
 pub struct Color {
     pub h: f32,
     pub s: f32,
 }
 
-#[derive(Debug, Deserialize)]
+/// The shapes a colour is accepted in. It's always stored as hue and saturation
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorRepresentation {
+    Hs { h: f32, s: f32 },
+    Rgb { r: u8, g: u8, b: u8 },
+}
+
+impl From<ColorRepresentation> for Color {
+    fn from(color: ColorRepresentation) -> Self {
+        match color {
+            ColorRepresentation::Hs { h, s } => Color { h, s },
+            ColorRepresentation::Rgb { r, g, b } => {
+                // the brightness is separate from the colour, so the RGB value's brightness is dropped
+                let hsv = Hsv::from_color(Srgb::new(r, g, b).into_format::<f32>());
+                Color {
+                    h: hsv.hue.into_positive_degrees(),
+                    s: hsv.saturation * 100.,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ControlMessage {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     color: Option<Color>,
-    #[serde(default)]
+    /// The colour as a colour temperature in mireds. An explicit colour takes precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color_temp: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     brightness: Option<u8>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     rainbow_speed: Option<f32>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     state: Option<OnState>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     mode: Option<Mode>,
+    /// The mode by its label or name, as Home Assistant's JSON light schema sends it. An explicit mode takes
+    /// precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    effect: Option<String>,
+    /// Cycles to the next mode. An explicit mode takes precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode_next: Option<bool>,
+    /// How long in seconds the change takes, for this change only. It takes precedence over a length in steps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transition: Option<f32>,
+    /// How long the change takes as a number of steps of the configured step duration, for this change only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transition_steps: Option<u32>,
+    /// How the brightness follows the transition's progress, for this change only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    easing: Option<Easing>,
+    /// Whether the transition fades the brightness and the colour, for this change only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transition_brightness: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transition_color: Option<bool>,
+    /// How the transition's colour gets to its target, for this change only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color_interpolation: Option<ColorInterpolation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    saturation_sweep: Option<SaturationSweep>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rainbow_range: Option<HueRange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rainbow_reverse: Option<bool>,
+    /// How many times a second the strobe mode flashes, between 0.5 and 20
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strobe_hz: Option<f32>,
+    /// Dips the brightness once and recovers it, to confirm something happened without changing anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ack: Option<bool>,
+    /// Flashes the light and returns it to what it was showing, without changing anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    flash: Option<Flash>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    capabilities: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<u8>,
+    /// When set along with a command, the command is scheduled to be applied once at this local time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    at: Option<NaiveDateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command: Option<Box<ControlMessage>>,
+}
+
+impl ControlMessage {
+    /// Parses a command from its JSON. A field that doesn't parse doesn't fail the whole command, instead it's treated
+    /// like an invalid field in [`ControlMessage::validated`]. An out-of-range brightness is clamped into range
+    fn from_payload(payload: &[u8], config: &Config) -> anyhow::Result<Self> {
+        let value = serde_json::from_slice::<serde_json::Value>(payload)?;
+        if let Ok(msg) = serde_json::from_value(value.clone()) {
+            return Ok(msg);
+        }
+
+        let serde_json::Value::Object(fields) = value else {
+            anyhow::bail!("command isn't a JSON object");
+        };
+
+        let mut valid = serde_json::Map::new();
+        let mut invalid = Vec::new();
+
+        for (name, mut value) in fields {
+            if name == "brightness" && !config.strict_commands {
+                if let Some(brightness) = value.as_f64() {
+                    let clamped = brightness.round().clamp(0., f64::from(u8::MAX));
+                    if clamped != brightness {
+                        warn!("Clamping brightness {brightness} in command to {clamped}");
+                        value = (clamped as u8).into();
+                    }
+                }
+            }
+
+            // each field is parsed on its own to tell which ones don't parse
+            let field = serde_json::Map::from_iter([(name.clone(), value.clone())]);
+            match serde_json::from_value::<ControlMessage>(field.into()) {
+                Ok(_) => {
+                    valid.insert(name, value);
+                }
+                Err(e) => invalid.push(format!("{name}: {e}")),
+            }
+        }
+
+        if !invalid.is_empty() {
+            let invalid = invalid.join(", ");
+            anyhow::ensure!(!config.strict_commands, "rejecting command: {invalid}");
+
+            warn!("Ignoring unparseable fields in command: {invalid}");
+        }
+
+        Ok(serde_json::from_value(valid.into())?)
+    }
+
+    /// How long in seconds the command's transition takes, if the command gives its length. A length in seconds takes
+    /// precedence over one in steps
+    fn transition_length(&self, config: &Config) -> Option<f32> {
+        self.transition
+            .or(self.transition_steps.map(|steps| steps as f32 * config.step_duration))
+    }
+
+    /// Whether the command changes the light by hand. A query, a priority on its own and the momentary acks and
+    /// flashes over what the light shows don't, so they neither cancel a sunrise, start the manual hold nor count as a
+    /// change during an all-off override
+    fn changes_light(&self) -> bool {
+        self.fields()
+            .iter()
+            .any(|field| !MOMENTARY_FIELDS.contains(&field.as_str()))
+    }
+
+    /// Whether the command only asks for the capabilities
+    fn is_query(&self) -> bool {
+        self.capabilities == Some(true) && self.fields() == ["capabilities"]
+    }
+
+    /// The names of the fields that are set, which are the ones that are serialised
+    fn fields(&self) -> Vec<String> {
+        match serde_json::to_value(self).expect("failed to serialize command") {
+            serde_json::Value::Object(fields) => fields.into_iter().map(|(name, _)| name).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks the fields that can't be made sense of. With strict commands, any invalid field rejects the whole
+    /// message. Otherwise the invalid fields are dropped and the rest of the message is applied. An effect is resolved
+    /// to its mode here as well
+    fn validated(mut self, config: &Config) -> anyhow::Result<Self> {
+        let mut invalid = Vec::new();
+
+        if let Some(color) = self.color {
+            if !(0. ..=360.).contains(&color.h) || !(0. ..=100.).contains(&color.s) {
+                invalid.push(format!("colour {color:?} is outside hue 0-360 and saturation 0-100"));
+                self.color = None;
+            }
+        }
+
+        if let Some(effect) = self.effect.take() {
+            match config.mode_for_label(&effect) {
+                Some(mode) => {
+                    self.mode.get_or_insert(mode);
+                }
+                None => invalid.push(format!("effect '{effect}' isn't any mode")),
+            }
+        }
+
+        if let Some(sweep) = self.saturation_sweep {
+            if !(0. ..=100.).contains(&sweep.min) || !(0. ..=100.).contains(&sweep.max) || sweep.min > sweep.max {
+                invalid.push(format!(
+                    "saturation sweep {sweep:?} isn't an ascending range within 0-100"
+                ));
+                self.saturation_sweep = None;
+            }
+        }
+
+        if let (Some(_), Some(steps)) = (self.transition, self.transition_steps) {
+            invalid.push(format!(
+                "transition of {steps} steps given along with a transition in seconds"
+            ));
+            self.transition_steps = None;
+        }
+
+        if invalid.is_empty() {
+            return Ok(self);
+        }
+
+        let invalid = invalid.join(", ");
+        anyhow::ensure!(!config.strict_commands, "rejecting command: {invalid}");
+
+        warn!("Ignoring invalid fields in command: {invalid}");
+        Ok(self)
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
+    if std::env::args().skip(1).any(|arg| arg == "--version") {
+        println!("moodlight {VERSION}");
+        return Ok(());
+    }
+
     if cfg!(debug_assertions) {
         dotenv::dotenv()?;
     }
 
-    env_logger::init();
+    init_logging(config::log_format()?);
+    info!("Moodlight {VERSION}");
+
+    // the lights update their configs when they're reloaded, so the panic colour is shown with the current ones
+    let mut configs = Config::load_lights()?;
+    let result = run(&mut configs).await;
+
+    if let Err(e) = &result {
+        error!("Exiting due to an error: {e}");
+
+        for config in &configs {
+            if let Some(color) = config.panic_color {
+                info!("Showing the panic colour on {}", config.name);
+                state::show_panic_color(color, config).await;
+            }
+        }
+    }
+
+    result
+}
+
+fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": Local::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+
+            writeln!(buf, "{line}")
+        });
+    }
+
+    builder.init();
+}
+
+/// Runs every light over a single connection to the broker, which the first light's config sets up. The events from
+/// the broker are shared with all the lights, and each light picks out the ones for its own topics. The connection's
+/// errors are handled here, so the lights keep running while it's reconnecting. A connection that stalls without an
+/// error is replaced with a new client, which the lights pick up from the watch channel
+async fn run(configs: &mut [Config]) -> anyhow::Result<()> {
+    // the connection's settings aren't reloaded, so the first light's config from startup sets it up for good
+    let connection_config = configs[0].clone();
+    let (client, mut eventloop) = create_mqtt_client(&connection_config).await?;
+    let (clients, _) = watch::channel(client);
+    let (events, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+
+    let mut reconnect_backoff = Duration::ZERO;
+    let mut reconnect_at = None;
+    let watchdog = Duration::from_secs_f32(connection_config.connection_watchdog);
+    let mut watchdog_at = time::Instant::now() + watchdog;
+
+    let lights = futures::future::try_join_all(
+        configs
+            .iter_mut()
+            .map(|config| run_light(config, clients.subscribe(), events.subscribe())),
+    );
+    futures::pin_mut!(lights);
+
+    let result = loop {
+        tokio::select! {
+            result = &mut lights => break result,
 
-    let config = Config::load()?;
-    let (client, mut eventloop) = create_mqtt_client(&config).await?;
+            // the event loop isn't polled while backing off, but the lights keep running
+            _ = time::sleep_until(reconnect_at.unwrap_or_else(time::Instant::now)), if reconnect_at.is_some() => {
+                reconnect_at = None;
+            }
+
+            _ = time::sleep_until(watchdog_at), if reconnect_at.is_none() => {
+                warn!("Nothing heard from the broker in {watchdog:?}, connecting to it again");
+
+                match create_mqtt_client(&connection_config).await {
+                    Ok((client, new_eventloop)) => {
+                        eventloop = new_eventloop;
+                        clients.send_replace(client);
+                    }
+                    Err(e) => error!("Failed to create a new MQTT client: {e}"),
+                }
+
+                watchdog_at = time::Instant::now() + watchdog;
+            }
+
+            event = eventloop.poll(), if reconnect_at.is_none() => {
+                match event {
+                    Ok(event) => {
+                        // the keep-alive pings go out whether or not the broker's there, so only what's heard from it
+                        // counts
+                        if let Event::Incoming(_) = event {
+                            watchdog_at = time::Instant::now() + watchdog;
+                        }
+
+                        if let Event::Incoming(Packet::ConnAck(_)) = event {
+                            reconnect_backoff = Duration::ZERO;
+                        }
+
+                        // the lights are always listening, since they only stop once this loop does
+                        let _ = events.send(event);
+                    }
+
+                    Err(e) => {
+                        reconnect_backoff = (reconnect_backoff * 2).clamp(MIN_RECONNECT_BACKOFF, MAX_RECONNECT_BACKOFF);
+                        error!("MQTT client returned error, polling again in {reconnect_backoff:?}: {e:?}");
+                        reconnect_at = Some(time::Instant::now() + reconnect_backoff);
+                        // the client's reconnecting by itself, so the silence while it backs off doesn't count
+                        watchdog_at = time::Instant::now() + reconnect_backoff + watchdog;
+                        // TODO: check what caused the error and break only if it's something unrecoverable
+                        // break;
+                    }
+                }
+            }
+        }
+    };
+
+    // the lights' last publishes don't actually go out until we poll the event loop enough times to empty the send
+    // queue. the ones with QoS 1 or 2 aren't delivered until the broker's acknowledged them, which for QoS 2 takes a
+    // handshake of its own, so the event loop is polled until each of them is acknowledged by its packet ID. QoS 0
+    // publishes don't have one. any sent earlier are still waited for by the count of publishes in flight
+    let mut unacknowledged = HashSet::new();
+    let flush = async {
+        loop {
+            // nothing new is sent when the event loop's pending, so the count from before polling holds
+            let inflight = eventloop.state.inflight();
+            let eventloop_poll = eventloop.poll();
+            futures::pin_mut!(eventloop_poll);
 
+            let res = match futures::poll!(&mut eventloop_poll) {
+                Poll::Ready(res) => res,
+                Poll::Pending if inflight == 0 && unacknowledged.is_empty() => break,
+                Poll::Pending => eventloop_poll.await,
+            };
+
+            match res {
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) if pkid != 0 => {
+                    unacknowledged.insert(pkid);
+                }
+
+                Ok(Event::Incoming(Packet::PubAck(PubAck { pkid, .. }) | Packet::PubComp(PubComp { pkid, .. }))) => {
+                    if unacknowledged.remove(&pkid) {
+                        debug!("Broker acknowledged publish {pkid}");
+                    }
+                }
+
+                Ok(event) => debug!("{event:?}"),
+                Err(e) => {
+                    warn!("MQTT client returned error while sending the last messages: {e:?}");
+                    break;
+                }
+            }
+        }
+    };
+
+    if time::timeout(SHUTDOWN_FLUSH_TIMEOUT, flush).await.is_err() {
+        warn!(
+            "The broker didn't acknowledge the last messages within {SHUTDOWN_FLUSH_TIMEOUT:?}, they may be lost. \
+             Unacknowledged packet IDs: {unacknowledged:?}"
+        );
+    }
+
+    debug!("Shutting down");
+    result.map(|_| ())
+}
+
+/// Runs a light until it's shut down. A reloaded config replaces the given one
+async fn run_light(
+    current_config: &mut Config,
+    mut clients: watch::Receiver<AsyncClient>,
+    mut events: broadcast::Receiver<Event>,
+) -> anyhow::Result<()> {
+    let config = &*current_config;
+    let mut reloaded_config = None;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut output = Output::new(config).await?;
     let mut state = State::default();
+    state.set_night_cap(schedule::night_brightness(config), 0., config);
+    let mut presence = Presence::default();
+    let mut all_off = AllOff::default();
+    let mut sunrise = Sunrise::default();
     let mut initial_state_received = false;
     let mut hass_discovery_sent = false;
+    let mut no_local = config.no_local;
+    let mut reconnects = 0;
+    let metrics = match config.metrics_port {
+        Some(port) => Some(metrics::serve(port).await?),
+        None => None,
+    };
+    let mut api = match config.api_port {
+        Some(port) => Some(api::serve(port).await?),
+        None => None,
+    };
+    let mut state_file_deadline = config
+        .state_file
+        .as_ref()
+        .map(|_| time::Instant::now() + Duration::from_secs_f32(config.state_file_timeout));
 
     // don't apply the default state, instead let the stored state in MQTT to be read and applied later
-    // state.apply(&config).await?;
+    // state.apply(config, &mut output).await?;
 
-    let mut rainbow_timer = time::interval(Duration::from_secs_f32(config.step_duration));
-    // set the missed tick behavior to Delay so when the rainbow timer should tick but doesn't, because the light is off
-    // or set to Static, any missed ticks are "ignored" and it'll start ticking regularly when active again
-    rainbow_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut step_timer = time::interval(Duration::from_secs_f32(config.step_duration));
+    // set the missed tick behavior to Delay so when the step timer should tick but doesn't, because nothing is being
+    // animated, any missed ticks are "ignored" and it'll start ticking regularly when active again
+    step_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut refresh_timer = config.refresh_interval.map(|interval| {
+        let mut timer = time::interval(Duration::from_secs_f32(interval));
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        timer
+    });
 
     let command_topic = config.command_topic();
+    let brightness_command_topic = config.brightness_command_topic();
     let state_topic = config.state_topic();
 
     loop {
+        let config = &*current_config;
+        // the client's replaced if its connection stalls
+        let client = clients.borrow().clone();
+        // the times to wait for are decided before waiting for them. for one, once a daily time is due its next time
+        // moves on to tomorrow, so which of the daily switches is due has to be known beforehand
+        let scheduled_at = state.next_scheduled();
+        let daily_switch = schedule::next_daily_switch(config);
+        let sunrise_at = Sunrise::next(config);
+        let night_change = schedule::next_night_change(config);
+        let publish_at = state.pending_publish_at(config).map(time::Instant::from_std);
+        let presence_deadline = presence.deadline();
+        let frame_at = output.pending_frame_at().map(time::Instant::from_std);
+
         tokio::select! {
             _ = wait_for_terminate() => break,
-            _ = rainbow_timer.tick(), if state.state == OnState::On && state.mode == Mode::Rainbow => {
-                state.step_hue(config.step_duration);
-                state.apply(&config).await?;
+            // the new client is taken at the top of the loop, before the new connection's events arrive
+            Ok(()) = clients.changed() => (),
+            _ = sighup.recv() => {
+                info!("Received hangup signal, reloading the config of {}", config.name);
+
+                match load_reloaded_config(config) {
+                    Ok(new_config) => reloaded_config = Some(new_config),
+                    Err(e) => error!("Reloading the config failed, keeping the current one: {e}"),
+                }
+            }
+
+            _ = step_timer.tick(), if state.is_animated() => {
+                state.step(config);
+                state.apply(config, &mut output).await?;
+                state.publish_hex(&client, config, true).await;
+
+                while let Some(msg) = state.take_queued_command() {
+                    apply_automation("queued command", msg, &mut state, &mut output, &client, config).await;
+                }
+            }
+
+            _ = tick(&mut refresh_timer), if !state.is_animated() => {
+                if let Err(e) = state::refresh_output(config, &mut output).await {
+                    error!("Output refresh failed: {e}");
+                }
+            }
+
+            _ = schedule::sleep_until(scheduled_at) => {
+                for msg in state.take_due_scheduled(schedule::now()) {
+                    if state.is_manually_held(config) {
+                        info!("Skipping scheduled command during the manual hold: {msg:?}");
+                        continue;
+                    }
+
+                    apply_automation("scheduled command", msg, &mut state, &mut output, &client, config).await;
+                }
+
+                // persist the removal of the fired commands
+                state.publish_to_mqtt(&client, &state_topic, config).await?;
+            }
+
+            _ = schedule::sleep_until(daily_switch.map(|(at, _)| at)) => {
+                let (_, on_state) = daily_switch.expect("no daily switch");
+
+                if state.is_manually_held(config) {
+                    info!("Skipping daily switch during the manual hold");
+                } else {
+                    let msg = ControlMessage {
+                        state: Some(on_state),
+                        ..Default::default()
+                    };
+                    apply_automation("daily switch", msg, &mut state, &mut output, &client, config).await;
+                }
+            }
+
+            _ = schedule::sleep_until(sunrise_at) => {
+                if state.is_manually_held(config) {
+                    info!("Skipping sunrise during the manual hold");
+                } else {
+                    let msg = sunrise.start(config);
+                    apply_automation("sunrise", msg, &mut state, &mut output, &client, config).await;
+                }
+            }
+
+            _ = schedule::sleep_until(night_change.map(|(at, _)| at)) => {
+                let (_, cap) = night_change.expect("no night change");
+                match cap {
+                    Some(cap) => info!("Capping the brightness at {cap} for the night"),
+                    None => info!("Lifting the night's brightness cap"),
+                }
+
+                state.set_night_cap(cap, config.transition_duration, config);
+
+                if let Err(e) = state.apply(config, &mut output).await {
+                    error!("Applying the night's brightness cap failed: {e}");
+                }
+
+                state.publish_to_mqtt(&client, &state_topic, config).await?;
+            }
+
+            _ = sleep_until_deadline(publish_at) => {
+                state.publish_to_mqtt_now(&client, &state_topic, config).await?;
+            }
+
+            _ = sleep_until_deadline(frame_at) => output.send_pending_frame().await,
+
+            _ = sleep_until_deadline(presence_deadline) => {
+                if let Some(msg) = presence.settle(&state, config) {
+                    if state.is_manually_held(config) {
+                        info!("Skipping presence command during the manual hold: {msg:?}");
+                    } else {
+                        apply_automation("presence command", msg, &mut state, &mut output, &client, config).await;
+                    }
+                }
+            }
+
+            _ = sleep_until_deadline(state_file_deadline) => {
+                state_file_deadline = None;
+
+                if !initial_state_received {
+                    if let Err(e) = restore_state_file(&mut state, &mut output, &client, config).await {
+                        error!("Restoring the state file failed: {e}");
+                    } else if let Some(msg) = all_off.state_restored(&state) {
+                        let what = "all-off command for the restored state";
+                        apply_automation(what, msg, &mut state, &mut output, &client, config).await;
+                    }
+
+                    // stop waiting for the state topic so a late state from there doesn't replace the restored one
+                    initial_state_received = true;
+
+                    if let Err(e) = client.unsubscribe(&state_topic).await {
+                        error!("Failed to unsubscribe from state topic: {e}");
+                    }
+                }
             }
 
-            event = eventloop.poll() => {
+            // the receiver is only polled when the API is served. the API's task never ends, so it's never closed
+            Some(request) = async { api.as_mut().expect("no API").recv().await }, if api.is_some() => {
+                let result = match request.command {
+                    Some(payload) => match ControlMessage::from_payload(&payload, config) {
+                        Ok(msg) => {
+                            let changes_light = msg.changes_light();
+                            if changes_light {
+                                sunrise.cancel(&mut state);
+                            }
+
+                            let result = process_command_message(
+                                msg,
+                                API_SOURCE,
+                                &mut state,
+                                &mut output,
+                                &client,
+                                config,
+                            )
+                            .await;
+                            if result.is_ok() {
+                                info!("API command processed. Current state: {state:?}");
+
+                                if changes_light {
+                                    all_off.changed_by_hand();
+                                }
+                            }
+
+                            result
+                        }
+                        Err(e) => Err(e),
+                    },
+                    None => Ok(()),
+                };
+
+                let reply = result.map(|_| state.to_published_value(config)).map_err(|e| {
+                    error!("API command processing failed: {e}");
+                    e.to_string()
+                });
+
+                // the client may have gone away already, there's nothing to do about that
+                let _ = request.reply.send(reply);
+            }
+
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Fell behind the broker's events, missed {missed} of them");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
                 match event {
-                    Ok(Event::Incoming(Packet::ConnAck(ack))) => {
+                    Event::Incoming(Packet::ConnAck(ack)) => {
                         info!("Connected to broker ({ack:?})");
 
+                        // the discovery is sent on the first connection, so it having been sent means this is a
+                        // reconnect
+                        if hass_discovery_sent {
+                            reconnects += 1;
+                        }
+
+                        client
+                            .publish(
+                                config.availability_topic(),
+                                config.availability_qos,
+                                config.availability_retain,
+                                AVAILABILITY_ONLINE,
+                            )
+                            .await?;
+
                         if !hass_discovery_sent {
-                            send_home_assistant_discovery(&config, &client).await?;
+                            if config.home_assistant_discovery {
+                                send_home_assistant_discovery(config, &client).await?;
+                            } else {
+                                clear_home_assistant_discovery(config, &client).await?;
+                            }
+
                             hass_discovery_sent = true;
                         }
 
                         // subscribe to the state topic only if we haven't yet received the initial state from there
-                        subscribe_to_own_topics(&config, &client, !initial_state_received).await?;
+                        subscribe_to_own_topics(config, &client, !initial_state_received, no_local).await?;
                     }
 
-                    Ok(Event::Incoming(Packet::SubAck(ack))) => info!("Subscribed to topic ({ack:?})"),
+                    Event::Incoming(Packet::SubAck(ack)) => {
+                        let rejected = ack
+                            .return_codes
+                            .iter()
+                            .any(|code| !matches!(code, SubscribeReasonCode::Success(_)));
 
-                    Ok(Event::Incoming(Packet::Publish(Publish { payload, topic, .. }))) => {
+                        if no_local && rejected {
+                            // the broker may have rejected the no-local option; subscribing again without it is
+                            // harmless if it didn't, since the same filters just replace the existing subscriptions
+                            warn!("Broker rejected a subscription ({ack:?}), subscribing again without no-local");
+                            no_local = false;
+                            subscribe_to_own_topics(config, &client, !initial_state_received, no_local).await?;
+                        } else {
+                            info!("Subscribed to topic ({ack:?})");
+                        }
+                    }
+
+                    Event::Incoming(Packet::Publish(Publish { payload, topic, retain, .. })) => {
                         let topic = String::from_utf8(topic.to_vec()).expect("non-UTF8 topic");
                         debug!("On {topic}: {payload:?}");
 
-                        if topic == command_topic {
-                            if let Err(e) = process_command_message(&payload, &mut state, &client, &state_topic, &config).await {
+                        if ignores_retained_command(&topic, retain, config) {
+                            info!("Ignoring retained command");
+                        } else if topic == command_topic {
+                            let msg = ControlMessage::from_payload(&payload, config);
+                            let changes_light = msg.as_ref().is_ok_and(ControlMessage::changes_light);
+                            if changes_light {
+                                sunrise.cancel(&mut state);
+                            }
+
+                            let result = match msg {
+                                Ok(msg) => {
+                                    process_command_message(
+                                        msg,
+                                        &command_topic,
+                                        &mut state,
+                                        &mut output,
+                                        &client,
+                                        config,
+                                    )
+                                    .await
+                                }
+                                Err(e) => Err(e),
+                            };
+
+                            if let Err(e) = result {
                                 error!("Command message processing failed: {e}");
                             } else {
                                 info!("Command message processed. Current state: {state:?}");
+
+                                if changes_light {
+                                    all_off.changed_by_hand();
+                                }
+                            }
+                        } else if config.legacy_brightness_topic && topic == brightness_command_topic {
+                            sunrise.cancel(&mut state);
+
+                            let result =
+                                process_brightness_message(&payload, &mut state, &mut output, &client, config).await;
+
+                            if let Err(e) = result {
+                                error!("Brightness command message processing failed: {e}");
+                            } else {
+                                info!("Brightness command message processed. Current state: {state:?}");
+                                all_off.changed_by_hand();
+                            }
+                        } else if config.presence_topic.as_deref() == Some(topic.as_str()) {
+                            presence.update(&payload, config);
+                        } else if config.all_off_topic.as_deref() == Some(topic.as_str()) {
+                            if let Some(msg) = all_off.update(&payload, &state) {
+                                let what = "all-off command";
+                                apply_automation(what, msg, &mut state, &mut output, &client, config).await;
                             }
                         } else if topic == state_topic {
-                            if let Err(e) = process_state_message(&payload, &mut state, &config).await {
+                            // without no-local, the broker echoes the state this light publishes back to it. the
+                            // light never publishes to its command topics, so the state topic is the only one to look
+                            // out for. if the echo arrives first, the retained state is now this light's own so
+                            // there's nothing else to wait for
+                            if state.is_own_publish(&payload) {
+                                debug!("Ignoring own state echoed back from the broker");
+                            } else if let Err(e) =
+                                process_state_message(&payload, &mut state, &mut output, &client, config).await
+                            {
                                 error!("State message processing failed: {e}");
+                            } else if let Some(msg) = all_off.state_restored(&state) {
+                                let what = "all-off command for the restored state";
+                                apply_automation(what, msg, &mut state, &mut output, &client, config).await;
                             }
 
                             initial_state_received = true;
@@ -124,45 +842,166 @@ async fn main() -> anyhow::Result<()> {
                             if let Err(e) = client.unsubscribe(&state_topic).await {
                                 error!("Failed to unsubscribe from state topic: {e}");
                             }
-                        } else {
+                        } else if config.lights.is_empty() {
                             warn!("Received message in unknown topic: {topic}");
+                        } else {
+                            // the other lights sharing the connection get their messages here as well
+                            debug!("Ignoring message in another light's topic: {topic}");
                         }
                     }
 
-                    Ok(_e) => {
+                    _e => {
                         // debug!("Unhandled event: {_e:?}");
                     }
-
-                    Err(e) => {
-                        error!("MQTT client returned error: {e:?}");
-                        // TODO: check what caused the error and break only if it's something unrecoverable
-                        // break;
-                    }
                 }
             }
         }
+
+        if let Some(metrics) = &metrics {
+            metrics.send_modify(|metrics| {
+                metrics.update_state(&state);
+                metrics.reconnects = reconnects;
+                metrics.write_errors = output.write_errors();
+            });
+        }
+
+        // the reloaded config is only taken once the loop's done with the current one
+        if let Some(new_config) = reloaded_config.take() {
+            apply_reloaded_config(
+                new_config,
+                current_config,
+                &mut state,
+                &mut output,
+                &mut step_timer,
+                &mut refresh_timer,
+            )
+            .await?;
+        }
+    }
+
+    let config = &*current_config;
+    let client = clients.borrow().clone();
+    info!("Shutting down {}; saving state to MQTT", config.name);
+
+    // a clean disconnect doesn't send the last will, so tell that the light's going away
+    if let Err(e) = client
+        .publish(
+            config.availability_topic(),
+            config.availability_qos,
+            config.availability_retain,
+            AVAILABILITY_OFFLINE,
+        )
+        .await
+    {
+        error!("Failed to publish availability: {e}");
     }
 
-    info!("Shutting down; saving state to MQTT");
+    if config.clear_discovery_on_shutdown {
+        if let Err(e) = clear_home_assistant_discovery(config, &client).await {
+            error!("Failed to clear Home Assistant discovery: {e}");
+        }
+    }
 
-    if let Err(e) = state.publish_to_mqtt(&client, &state_topic).await {
+    if let Err(e) = state.publish_to_mqtt_now(&client, &state_topic, config).await {
         error!("Failed to save state to MQTT: {e}");
-    } else {
-        // the publish doesn't actually go out until we poll the event loop enough times to empty the send queue
+    }
 
-        loop {
-            let eventloop_poll = eventloop.poll();
-            futures::pin_mut!(eventloop_poll);
+    Ok(())
+}
 
-            match futures::poll!(eventloop_poll) {
-                Poll::Ready(res) => debug!("{res:?}"),
-                Poll::Pending => break,
-            }
+/// Loads the config again and picks out the light's own. Only the config file can have changed, since the
+/// environment variables are the ones the light was started with
+fn load_reloaded_config(config: &Config) -> anyhow::Result<Config> {
+    Config::load_lights()?
+        .into_iter()
+        .find(|new_config| new_config.name == config.name)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "light '{}' is gone from the config; removing it needs a restart",
+                config.name
+            )
+        })
+}
+
+/// Takes the settings of a reloaded config that can be changed while running, and shows the light with them
+async fn apply_reloaded_config(
+    new_config: Config,
+    config: &mut Config,
+    state: &mut State,
+    output: &mut Output,
+    step_timer: &mut time::Interval,
+    refresh_timer: &mut Option<time::Interval>,
+) -> anyhow::Result<()> {
+    let channels_changed = (config.pin_r, config.pin_g, config.pin_b, config.pwm_period)
+        != (
+            new_config.pin_r,
+            new_config.pin_g,
+            new_config.pin_b,
+            new_config.pwm_period,
+        );
+
+    // the old pins would otherwise be left showing the last colour
+    if channels_changed {
+        if let Err(e) = state::blank_output(config, output).await {
+            error!("Failed to turn off the old pins: {e}");
         }
     }
 
-    debug!("Shutting down");
-    Ok(())
+    let reloaded = config.reload(new_config);
+
+    if reloaded.applied.is_empty() && reloaded.need_restart.is_empty() {
+        info!("Config reloaded without changes");
+    }
+
+    if !reloaded.applied.is_empty() {
+        info!("Config reloaded, applied changes to: {}", reloaded.applied.join(", "));
+    }
+
+    if !reloaded.need_restart.is_empty() {
+        warn!(
+            "Changes to {} only take effect after a restart",
+            reloaded.need_restart.join(", ")
+        );
+    }
+
+    if channels_changed {
+        output.replace_light(config);
+    }
+
+    if reloaded.applied.contains(&"step_duration") {
+        *step_timer = time::interval(Duration::from_secs_f32(config.step_duration));
+        step_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    }
+
+    if reloaded.applied.contains(&"refresh_interval") {
+        *refresh_timer = config.refresh_interval.map(|interval| {
+            let mut timer = time::interval(Duration::from_secs_f32(interval));
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            timer
+        });
+    }
+
+    // the night's times or cap may have changed
+    state.set_night_cap(schedule::night_brightness(config), config.transition_duration, config);
+    state.apply(config, output).await
+}
+
+/// Waits until the deadline, or forever without one
+async fn sleep_until_deadline(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the timer's next tick, or forever without the timer
+async fn tick(timer: &mut Option<time::Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
 }
 
 async fn wait_for_terminate() -> anyhow::Result<()> {
@@ -182,7 +1021,25 @@ async fn create_mqtt_client(config: &Config) -> anyhow::Result<(AsyncClient, Eve
     let mut mqtt_options = MqttOptions::parse_url(&config.broker_url)?;
     mqtt_options
         .set_credentials(&config.broker_username, &config.broker_password)
-        .set_keep_alive(Duration::from_secs(10));
+        .set_keep_alive(Duration::from_secs(10))
+        .set_last_will(LastWill::new(
+            config.availability_topic(),
+            AVAILABILITY_OFFLINE,
+            config.availability_qos,
+            config.availability_retain,
+            None,
+        ));
+
+    // an mqtts:// URL already uses TLS with the system's root certificates. a custom CA, e.g. for a self-signed
+    // broker, or the TLS flag switch any URL to TLS
+    if let Some(ca) = &config.broker_ca {
+        let ca = tokio::fs::read(ca)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read broker CA {}: {e}", ca.display()))?;
+        mqtt_options.set_transport(Transport::tls(ca, None, None));
+    } else if config.broker_tls {
+        mqtt_options.set_transport(Transport::tls_with_default_config());
+    }
 
     let (client, eventloop) = AsyncClient::new(mqtt_options, 10);
     Ok((client, eventloop))
@@ -206,8 +1063,8 @@ async fn send_home_assistant_discovery(config: &Config, client: &AsyncClient) ->
     client
         .publish(
             config.home_assistant_light_topic(),
-            QoS::AtLeastOnce,
-            true,
+            config.discovery_qos,
+            config.discovery_retain,
             light_config_json,
         )
         .await?;
@@ -215,8 +1072,8 @@ async fn send_home_assistant_discovery(config: &Config, client: &AsyncClient) ->
     client
         .publish(
             config.home_assistant_select_topic(),
-            QoS::AtLeastOnce,
-            true,
+            config.discovery_qos,
+            config.discovery_retain,
             select_config_json,
         )
         .await?;
@@ -224,8 +1081,8 @@ async fn send_home_assistant_discovery(config: &Config, client: &AsyncClient) ->
     client
         .publish(
             config.home_assistant_number_topic(),
-            QoS::AtLeastOnce,
-            true,
+            config.discovery_qos,
+            config.discovery_retain,
             number_config_json,
         )
         .await?;
@@ -233,25 +1090,80 @@ async fn send_home_assistant_discovery(config: &Config, client: &AsyncClient) ->
     Ok(())
 }
 
+/// Removes the light from Home Assistant by clearing its retained discovery configs
+async fn clear_home_assistant_discovery(config: &Config, client: &AsyncClient) -> anyhow::Result<()> {
+    info!("Clearing Home Assistant MQTT discovery messages");
+
+    for topic in [
+        config.home_assistant_light_topic(),
+        config.home_assistant_select_topic(),
+        config.home_assistant_number_topic(),
+    ] {
+        client.publish(topic, config.discovery_qos, true, "").await?;
+    }
+
+    Ok(())
+}
+
+async fn publish_capabilities(config: &Config, client: &AsyncClient) -> anyhow::Result<()> {
+    let capabilities = Capabilities::new(config);
+    debug!("{capabilities:?}");
+
+    let capabilities_json = serde_json::to_string(&capabilities).expect("failed to serialize capabilities");
+
+    info!("Publishing capabilities");
+    client
+        .publish(config.capabilities_topic(), QoS::AtLeastOnce, false, capabilities_json)
+        .await?;
+
+    Ok(())
+}
+
 async fn subscribe_to_own_topics(
     config: &Config,
     client: &AsyncClient,
     include_state_topic: bool,
+    no_local: bool,
 ) -> anyhow::Result<()> {
     info!("Subscribing to own topics under {}", config.own_topic());
 
     let mut topics = vec![Filter {
         path: config.command_topic(),
         qos: QoS::AtLeastOnce,
-        nolocal: true,
+        nolocal: no_local,
         ..Default::default()
     }];
 
+    if config.legacy_brightness_topic {
+        topics.push(Filter {
+            path: config.brightness_command_topic(),
+            qos: QoS::AtLeastOnce,
+            nolocal: no_local,
+            ..Default::default()
+        });
+    }
+
+    if let Some(presence_topic) = &config.presence_topic {
+        topics.push(Filter {
+            path: presence_topic.clone(),
+            qos: QoS::AtLeastOnce,
+            ..Default::default()
+        });
+    }
+
+    if let Some(all_off_topic) = &config.all_off_topic {
+        topics.push(Filter {
+            path: all_off_topic.clone(),
+            qos: QoS::AtLeastOnce,
+            ..Default::default()
+        });
+    }
+
     if include_state_topic {
         topics.push(Filter {
             path: config.state_topic(),
             qos: QoS::AtLeastOnce,
-            nolocal: true,
+            nolocal: no_local,
             ..Default::default()
         });
     }
@@ -260,33 +1172,516 @@ async fn subscribe_to_own_topics(
     Ok(())
 }
 
+/// Whether a message is a retained command that's ignored. A command retained by mistake is delivered again on every
+/// reconnect, undoing whatever the light has been set to since, so it's warned about even if it's applied
+fn ignores_retained_command(topic: &str, retain: bool, config: &Config) -> bool {
+    let is_command_topic = topic == config.command_topic()
+        || (config.legacy_brightness_topic && topic == config.brightness_command_topic());
+
+    if !(is_command_topic && retain) {
+        return false;
+    }
+
+    warn!(
+        "Received a retained command on {topic}. Commands shouldn't be retained since they're applied again on every \
+         reconnect; clear it by publishing an empty retained message"
+    );
+    config.ignore_retained_commands
+}
+
+/// Processes a command from a source, which is either the command topic or the REST API
 async fn process_command_message(
+    msg: ControlMessage,
+    source: &str,
+    state: &mut State,
+    output: &mut Output,
+    client: &AsyncClient,
+    config: &Config,
+) -> anyhow::Result<()> {
+    info!("Received command message: {msg:?}",);
+
+    if msg.capabilities == Some(true) {
+        publish_capabilities(config, client).await?;
+
+        // a query on its own leaves the light be
+        if msg.is_query() {
+            return Ok(());
+        }
+    }
+
+    let changes_light = msg.changes_light();
+    let old = state.clone();
+    apply_control_message(msg, state, output, client, &config.state_topic(), config).await?;
+
+    if changes_light {
+        state.mark_manual_change();
+    }
+
+    changelog::append(config, source, &old, state).await;
+
+    Ok(())
+}
+
+async fn process_brightness_message(
     payload: &[u8],
     state: &mut State,
+    output: &mut Output,
+    client: &AsyncClient,
+    config: &Config,
+) -> anyhow::Result<()> {
+    // the non-JSON light schema sends the brightness as a bare integer. parse it wider than the brightness itself so
+    // out-of-range values can be told apart from garbage
+    let payload = std::str::from_utf8(payload)?.trim();
+    let brightness = payload
+        .parse::<i64>()
+        .map_err(|e| anyhow::anyhow!("invalid brightness '{payload}': {e}"))?;
+    let brightness =
+        u8::try_from(brightness).map_err(|_| anyhow::anyhow!("brightness {brightness} is outside 0-{}", u8::MAX))?;
+
+    info!("Received brightness command message: {brightness}");
+
+    let msg = ControlMessage {
+        brightness: Some(brightness),
+        ..Default::default()
+    };
+
+    let old = state.clone();
+    apply_control_message(msg, state, output, client, &config.state_topic(), config).await?;
+    state.mark_manual_change();
+    changelog::append(config, &config.brightness_command_topic(), &old, state).await;
+
+    Ok(())
+}
+
+/// Applies a command from one of the light's automations. There's no one to report a failure to but the log
+async fn apply_automation(
+    what: &str,
+    msg: ControlMessage,
+    state: &mut State,
+    output: &mut Output,
+    client: &AsyncClient,
+    config: &Config,
+) {
+    info!("Applying {what}: {msg:?}");
+
+    if let Err(e) = apply_control_message(msg, state, output, client, &config.state_topic(), config).await {
+        error!("Failed to apply the {what}: {e}");
+    }
+}
+
+async fn apply_control_message(
+    msg: ControlMessage,
+    state: &mut State,
+    output: &mut Output,
     client: &AsyncClient,
     state_topic: &str,
     config: &Config,
 ) -> anyhow::Result<()> {
-    let msg = serde_json::from_slice::<ControlMessage>(payload)?;
-    info!("Received command message: {msg:?}",);
+    if let (Some(at), Some(command)) = (msg.at, &msg.command) {
+        let command = (**command).clone();
 
-    state.edit(msg);
+        if at > schedule::now() {
+            info!("Scheduling command for {at}: {command:?}");
+            state.schedule(at, command);
+            state.publish_to_mqtt(client, state_topic, config).await?;
+            return Ok(());
+        }
 
-    // applying the state may result in a delay due to the transition time from one on state to another. since during
-    // that time we're not polling the MQTT event loop, our outgoing publish is sent after the apply is done regardless
-    // when we call publish, but send it afterwards in case the apply fails
-    state.apply(config).await?;
-    state.publish_to_mqtt(client, state_topic).await?;
+        match config.past_schedule {
+            PastSchedulePolicy::Fire => {
+                info!("Scheduled time {at} is in the past, applying the command immediately");
+                return Box::pin(apply_control_message(
+                    command,
+                    state,
+                    output,
+                    client,
+                    state_topic,
+                    config,
+                ))
+                .await;
+            }
+            PastSchedulePolicy::Ignore => {
+                warn!("Ignoring command scheduled for {at} which is in the past");
+                return Ok(());
+            }
+        }
+    }
+
+    let msg = msg.validated(config)?;
+    let priority = msg.priority.unwrap_or(0);
+
+    if state.is_held(priority) {
+        info!("Ignoring command with priority {priority} while a command with a higher priority holds");
+        return Ok(());
+    }
+
+    if config.transition_overlap == TransitionOverlap::Queue && state.in_transition() {
+        info!("Queueing command until the transition in progress completes");
+        state.queue_command(msg);
+        return Ok(());
+    }
+
+    let previous = state.clone();
+    state.edit(msg, config);
+
+    // send the publish after applying in case the apply fails. if it does fail, the light is most likely still showing
+    // the previous state, so roll back to it to not publish or build on a state that was never shown
+    if let Err(e) = state.apply(config, output).await {
+        *state = previous;
+        return Err(e);
+    }
+
+    state.publish_to_mqtt(client, state_topic, config).await?;
+    state.publish_hex(client, config, false).await;
 
     Ok(())
 }
 
-async fn process_state_message(payload: &[u8], state: &mut State, config: &Config) -> anyhow::Result<()> {
-    let new_state = serde_json::from_slice::<State>(payload)?;
+async fn restore_state_file(
+    state: &mut State,
+    output: &mut Output,
+    client: &AsyncClient,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let Some(path) = &config.state_file else {
+        return Ok(());
+    };
+
+    match state_file::load(path).await? {
+        Some(state_json) => {
+            info!("No state received from the state topic, restoring the state file");
+            process_state_message(&state_json, state, output, client, config).await
+        }
+        None => {
+            info!("No state received from the state topic and there's no state file yet");
+            Ok(())
+        }
+    }
+}
+
+async fn process_state_message(
+    payload: &[u8],
+    state: &mut State,
+    output: &mut Output,
+    client: &AsyncClient,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let mut new_state = serde_json::from_slice::<State>(payload)?;
     info!("Received initial state: {new_state:?}");
 
-    *state = new_state;
-    state.apply(config).await?;
+    new_state.migrate();
+
+    // commands that were scheduled for while the light was down are due as soon as the loop gets to them, unless they
+    // should be ignored
+    if config.past_schedule == PastSchedulePolicy::Ignore {
+        for msg in new_state.take_due_scheduled(schedule::now()) {
+            warn!("Ignoring command scheduled for the past: {msg:?}");
+        }
+    }
+
+    // the restored state is shown capped right away if it's the night
+    new_state.set_night_cap(schedule::night_brightness(config), 0., config);
+
+    let previous = std::mem::replace(state, new_state);
+
+    if let Err(e) = state.apply(config, output).await {
+        *state = previous;
+        return Err(e);
+    }
+
+    state.publish_hex(client, config, false).await;
+    changelog::append(config, &config.state_topic(), &previous, state).await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::Recording;
+
+    fn command(payload: &str) -> ControlMessage {
+        ControlMessage::from_payload(payload.as_bytes(), &Config::for_tests(&[])).expect("invalid command")
+    }
+
+    #[test]
+    fn momentary_commands_dont_change_light() {
+        for payload in [
+            r#"{"capabilities": true}"#,
+            r#"{"ack": true}"#,
+            r#"{"flash": "short"}"#,
+            r#"{"flash": {"times": 3}}"#,
+            r#"{"ack": true, "priority": 5}"#,
+        ] {
+            assert!(!command(payload).changes_light(), "{payload}");
+        }
+
+        for payload in [
+            r#"{"state": "ON"}"#,
+            r#"{"ack": true, "brightness": 10}"#,
+            r#"{"flash": "long", "mode": "Rainbow"}"#,
+        ] {
+            assert!(command(payload).changes_light(), "{payload}");
+        }
+    }
+
+    #[test]
+    fn capabilities_query() {
+        assert!(command(r#"{"capabilities": true}"#).is_query());
+        assert!(!command(r#"{"capabilities": false}"#).is_query());
+        assert!(!command(r#"{"capabilities": true, "state": "OFF"}"#).is_query());
+    }
+
+    #[test]
+    fn transition_given_twice() {
+        let payload = r#"{"transition": 2, "transition_steps": 10}"#;
+
+        let lenient = command(payload).validated(&Config::for_tests(&[])).expect("rejected");
+        assert_eq!(lenient.transition, Some(2.));
+        assert_eq!(lenient.transition_steps, None);
+
+        let strict = Config::for_tests(&[("STRICT_COMMANDS", "true")]);
+        assert!(command(payload).validated(&strict).is_err());
+    }
+
+    /// A client whose requests are kept for inspecting instead of being sent to a broker
+    fn client() -> (AsyncClient, flume::Receiver<rumqttc::v5::Request>) {
+        let (requests_tx, requests_rx) = flume::unbounded();
+        (AsyncClient::from_senders(requests_tx), requests_rx)
+    }
+
+    #[tokio::test]
+    async fn failed_apply_rolls_back() {
+        let config = Config::for_tests(&[]);
+        let (client, requests) = client();
+        let mut output = Output::with_light(Box::new(Recording {
+            fail: true,
+            ..Default::default()
+        }));
+        let mut state = State::default();
+        let previous = serde_json::to_value(&state).expect("failed to serialise state");
+
+        let result = apply_control_message(
+            command(r#"{"state": "ON", "brightness": 100}"#),
+            &mut state,
+            &mut output,
+            &client,
+            &config.state_topic(),
+            &config,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(
+            serde_json::to_value(&state).expect("failed to serialise state"),
+            previous
+        );
+        assert!(requests.is_empty());
+
+        // the same command is published once the light takes it
+        let mut output = Output::with_light(Box::<Recording>::default());
+        apply_control_message(
+            command(r#"{"state": "ON", "brightness": 100}"#),
+            &mut state,
+            &mut output,
+            &client,
+            &config.state_topic(),
+            &config,
+        )
+        .await
+        .expect("apply failed");
+        assert!(!requests.is_empty());
+    }
+
+    /// Takes the steps the loop would until every transition and queued command is done
+    async fn settle(state: &mut State, output: &mut Output, client: &AsyncClient, config: &Config) {
+        for _ in 0..1000 {
+            if !state.is_animated() {
+                break;
+            }
+
+            state.step(config);
+            state.apply(config, output).await.expect("apply failed");
+            while let Some(msg) = state.take_queued_command() {
+                apply_control_message(msg, state, output, client, &config.state_topic(), config)
+                    .await
+                    .expect("apply failed");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn overlapping_transitions_end_at_the_last_command() {
+        let commands = [
+            r#"{"state": "ON", "brightness": 50, "color": {"h": 0, "s": 100}, "transition": 1}"#,
+            r#"{"brightness": 150, "color": {"h": 120, "s": 100}, "transition": 1}"#,
+            r#"{"brightness": 250, "color": {"h": 330, "s": 40}, "transition": 1}"#,
+        ];
+
+        for overlap in ["cancel", "queue"] {
+            let config = Config::for_tests(&[("TRANSITION_OVERLAP", overlap)]);
+            let (client, _requests) = client();
+            let topic = config.state_topic();
+
+            // the last command applied on its own shows what the light should end up at
+            let expected = Recording::default();
+            let mut output = Output::with_light(Box::new(expected.clone()));
+            let mut expected_state = State::default();
+            let last = command(r#"{"state": "ON", "brightness": 250, "color": {"h": 330, "s": 40}}"#);
+            apply_control_message(last, &mut expected_state, &mut output, &client, &topic, &config)
+                .await
+                .expect("apply failed");
+            settle(&mut expected_state, &mut output, &client, &config).await;
+
+            let shown = Recording::default();
+            let mut output = Output::with_light(Box::new(shown.clone()));
+            let mut state = State::default();
+            for payload in commands {
+                apply_control_message(command(payload), &mut state, &mut output, &client, &topic, &config)
+                    .await
+                    .expect("apply failed");
+            }
+
+            settle(&mut state, &mut output, &client, &config).await;
+            assert!(!state.is_animated(), "{overlap}");
+            assert_eq!(state.brightness, expected_state.brightness, "{overlap}");
+            assert_eq!(state.color, expected_state.color, "{overlap}");
+            assert_eq!(
+                shown.writes.lock().unwrap().last(),
+                expected.writes.lock().unwrap().last(),
+                "{overlap}"
+            );
+        }
+    }
+
+    #[test]
+    fn strict_and_lenient_commands() {
+        let strict = Config::for_tests(&[("STRICT_COMMANDS", "true")]);
+        let lenient = Config::for_tests(&[]);
+        let parse = |payload: &str, config| {
+            ControlMessage::from_payload(payload.as_bytes(), config).and_then(|msg| msg.validated(config))
+        };
+
+        // an out-of-range colour parses, but doesn't validate
+        let payload = r#"{"state": "ON", "color": {"h": 400, "s": 50}}"#;
+        assert!(parse(payload, &strict).is_err());
+        let msg = parse(payload, &lenient).expect("lenient command rejected");
+        assert_eq!(msg.state, Some(OnState::On));
+        assert_eq!(msg.color, None);
+
+        // an unknown mode doesn't parse at all
+        let payload = r#"{"state": "ON", "mode": "Disco", "brightness": 30}"#;
+        assert!(parse(payload, &strict).is_err());
+        let msg = parse(payload, &lenient).expect("lenient command rejected");
+        assert_eq!(
+            (msg.state, msg.mode, msg.brightness),
+            (Some(OnState::On), None, Some(30))
+        );
+
+        // a valid command is the same either way
+        let payload = r#"{"state": "ON", "color": {"h": 200, "s": 50}}"#;
+        let msg = parse(payload, &strict).expect("valid command rejected");
+        assert_eq!(msg.color, Some(Color { h: 200., s: 50. }));
+    }
+
+    #[test]
+    fn retained_commands() {
+        for (ignore, legacy) in [(false, false), (true, false), (true, true)] {
+            let config = Config::for_tests(&[
+                ("IGNORE_RETAINED_COMMANDS", if ignore { "true" } else { "false" }),
+                ("LEGACY_BRIGHTNESS_TOPIC", if legacy { "true" } else { "false" }),
+            ]);
+            let case = (ignore, legacy);
+
+            assert_eq!(
+                ignores_retained_command(&config.command_topic(), true, &config),
+                ignore,
+                "{case:?}"
+            );
+            assert!(
+                !ignores_retained_command(&config.command_topic(), false, &config),
+                "{case:?}"
+            );
+            assert_eq!(
+                ignores_retained_command(&config.brightness_command_topic(), true, &config),
+                ignore && legacy,
+                "{case:?}"
+            );
+
+            // the retained state isn't a command
+            assert!(
+                !ignores_retained_command(&config.state_topic(), true, &config),
+                "{case:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rgb_color_round_trip() {
+        let config = Config::for_tests(&[]);
+        let (client, _requests) = client();
+
+        for (payload, expected) in [
+            (
+                r#"{"state": "ON", "color": {"r": 255, "g": 128, "b": 0}}"#,
+                (1., 128. / 255., 0.),
+            ),
+            (
+                r#"{"state": "ON", "color": {"r": 0, "g": 51, "b": 255}}"#,
+                (0., 0.2, 1.),
+            ),
+            // the RGB value's own brightness is dropped for the commanded one
+            (r#"{"state": "ON", "color": {"r": 128, "g": 0, "b": 0}}"#, (1., 0., 0.)),
+        ] {
+            let shown = Recording::default();
+            let mut output = Output::with_light(Box::new(shown.clone()));
+            let mut state = State::default();
+            apply_control_message(
+                command(payload),
+                &mut state,
+                &mut output,
+                &client,
+                &config.state_topic(),
+                &config,
+            )
+            .await
+            .expect("apply failed");
+            settle(&mut state, &mut output, &client, &config).await;
+
+            let writes = shown.writes.lock().unwrap();
+            let (rgb, _) = writes.last().expect("nothing written");
+            let (red, green, blue) = expected;
+            assert!(
+                (rgb.red - red).abs() < 1e-3 && (rgb.green - green).abs() < 1e-3 && (rgb.blue - blue).abs() < 1e-3,
+                "{payload}: {rgb:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn partly_invalid_payloads() {
+        let strict = Config::for_tests(&[("STRICT_COMMANDS", "true")]);
+        let lenient = Config::for_tests(&[]);
+
+        for (payload, brightness) in [
+            (r#"{"brightness": 300, "state": "ON"}"#, Some(255)),
+            (r#"{"brightness": -5, "state": "ON"}"#, Some(0)),
+            (r#"{"brightness": 99.6, "state": "ON"}"#, Some(100)),
+            (r#"{"brightness": "bright", "state": "ON"}"#, None),
+        ] {
+            assert!(
+                ControlMessage::from_payload(payload.as_bytes(), &strict).is_err(),
+                "{payload}"
+            );
+
+            // the valid fields are kept, and the brightness clamped into range if it's a number at all
+            let msg = ControlMessage::from_payload(payload.as_bytes(), &lenient).expect("lenient command rejected");
+            assert_eq!(msg.state, Some(OnState::On), "{payload}");
+            assert_eq!(msg.brightness, brightness, "{payload}");
+        }
+
+        // a payload that isn't an object has no valid fields to keep
+        assert!(ControlMessage::from_payload(b"[1, 2]", &lenient).is_err());
+        assert!(ControlMessage::from_payload(b"{", &lenient).is_err());
+    }
+}
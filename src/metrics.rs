@@ -0,0 +1,111 @@
+use std::fmt::Write;
+
+use log::*;
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{http, state::State, OnState};
+
+/// The values served to Prometheus. The light's loop keeps them up to date, and the metrics server only reads them
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    pub brightness: u8,
+    pub hue: f32,
+    pub saturation: f32,
+    pub on: bool,
+    pub mode: &'static str,
+    /// How many times the light has connected to the broker again after its first connection
+    pub reconnects: u64,
+    /// How many writes to the light's output have failed
+    pub write_errors: u64,
+}
+
+impl Metrics {
+    pub fn update_state(&mut self, state: &State) {
+        self.brightness = state.brightness;
+        self.hue = state.color.h;
+        self.saturation = state.color.s;
+        self.on = state.state == OnState::On;
+        self.mode = state.mode.name();
+    }
+
+    fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        for (name, help, value) in [
+            (
+                "moodlight_brightness",
+                "The brightness, between 0 and 255",
+                f64::from(self.brightness),
+            ),
+            ("moodlight_hue", "The colour's hue in degrees", f64::from(self.hue)),
+            (
+                "moodlight_saturation",
+                "The colour's saturation, between 0 and 100",
+                f64::from(self.saturation),
+            ),
+            ("moodlight_on", "Whether the light is on", f64::from(u8::from(self.on))),
+        ] {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+        }
+
+        let _ = write!(
+            out,
+            "# HELP moodlight_mode The current mode\n# TYPE moodlight_mode gauge\nmoodlight_mode{{mode=\"{}\"}} 1\n",
+            self.mode
+        );
+
+        for (name, help, value) in [
+            (
+                "moodlight_mqtt_reconnects_total",
+                "Reconnections to the MQTT broker",
+                self.reconnects,
+            ),
+            (
+                "moodlight_write_errors_total",
+                "Failed writes to the light's output",
+                self.write_errors,
+            ),
+        ] {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n");
+        }
+
+        out
+    }
+}
+
+/// Starts serving the metrics on a port in a task of its own, returning the sender the metrics are updated through
+pub async fn serve(port: u16) -> anyhow::Result<watch::Sender<Metrics>> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    let (sender, receiver) = watch::channel(Metrics::default());
+    info!("Serving metrics on port {port}");
+
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {e}");
+                    continue;
+                }
+            };
+
+            let receiver = receiver.clone();
+            tokio::spawn(async move {
+                let result = match http::read_request(&mut stream).await {
+                    Ok(request) if request.method == "GET" && request.path == "/metrics" => {
+                        let body = receiver.borrow().to_prometheus();
+                        http::write_response(&mut stream, "200 OK", "text/plain; version=0.0.4", body.as_bytes()).await
+                    }
+                    Ok(_) => http::write_response(&mut stream, "404 Not Found", "text/plain", b"Not found\n").await,
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = result {
+                    debug!("Metrics request failed: {e}");
+                }
+            });
+        }
+    });
+
+    Ok(sender)
+}
@@ -0,0 +1,209 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use log::*;
+use palette::rgb::Rgb;
+use tokio::net::UdpSocket;
+
+use crate::{
+    config::Config,
+    sink::{self, LightSink},
+};
+
+// frames are computed every step, but a burst of writes (e.g. quick successive commands) shouldn't flood the sink
+const UDP_SINK_MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runtime resources for everything the computed colours are written to.
+#[derive(Debug)]
+pub struct Output {
+    light: Box<dyn LightSink>,
+    udp_sink: Option<UdpSink>,
+    /// The last frame written to the light
+    last_frame: Option<Rgb>,
+    /// How many writes to the light have failed
+    write_errors: u64,
+}
+
+#[derive(Debug)]
+struct UdpSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+    last_frame: Option<(Instant, [u8; 3])>,
+    /// The latest frame held back for coming too soon after the last one, sent once the interval is over
+    pending_frame: Option<[u8; 3]>,
+}
+
+impl Output {
+    pub async fn new(config: &Config) -> anyhow::Result<Self> {
+        let udp_sink = match config.udp_sink {
+            Some(target) => Some(UdpSink::new(target).await?),
+            None => None,
+        };
+
+        Ok(Self {
+            light: sink::from_config(config),
+            udp_sink,
+            last_frame: None,
+            write_errors: 0,
+        })
+    }
+
+    /// An output writing only to the given sink
+    #[cfg(test)]
+    pub fn with_light(light: Box<dyn LightSink>) -> Self {
+        Self {
+            light,
+            udp_sink: None,
+            last_frame: None,
+            write_errors: 0,
+        }
+    }
+
+    /// Replaces the light's sink with a new one for a changed config, e.g. so the PWM sink sets up its new channels
+    pub fn replace_light(&mut self, config: &Config) {
+        self.light = sink::from_config(config);
+    }
+
+    pub fn last_frame(&self) -> Option<Rgb> {
+        self.last_frame
+    }
+
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors
+    }
+
+    /// Writes the channels' duty cycles to the light
+    pub async fn write_light(&mut self, rgb: Rgb, white: Option<f32>, config: &Config) -> anyhow::Result<()> {
+        let result = self.light.write(rgb, white, config).await;
+        if result.is_err() {
+            self.write_errors += 1;
+        }
+
+        result
+    }
+
+    /// Emits a computed frame to the configured sinks. Failing to do so is logged but never fails the write to the
+    /// light itself.
+    pub async fn send_frame(&mut self, rgb: Rgb) {
+        self.last_frame = Some(rgb);
+
+        if let Some(sink) = &mut self.udp_sink {
+            if let Err(e) = sink.send(rgb).await {
+                warn!("Failed to send frame to UDP sink {}: {e}", sink.target);
+            }
+        }
+    }
+
+    /// When the frame held back from the UDP sink is due, if there is one
+    pub fn pending_frame_at(&self) -> Option<Instant> {
+        self.udp_sink.as_ref().and_then(UdpSink::pending_frame_at)
+    }
+
+    /// Sends the frame held back from the UDP sink
+    pub async fn send_pending_frame(&mut self) {
+        if let Some(sink) = &mut self.udp_sink {
+            if let Err(e) = sink.send_pending().await {
+                warn!("Failed to send frame to UDP sink {}: {e}", sink.target);
+            }
+        }
+    }
+}
+
+impl UdpSink {
+    async fn new(target: SocketAddr) -> anyhow::Result<Self> {
+        let bind_addr: SocketAddr = if target.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+
+        let socket = UdpSocket::bind(bind_addr).await?;
+        info!("Sending frames to UDP sink {target}");
+
+        Ok(Self {
+            socket,
+            target,
+            last_frame: None,
+            pending_frame: None,
+        })
+    }
+
+    async fn send(&mut self, rgb: Rgb) -> anyhow::Result<()> {
+        // the packet is simply the three channels as bytes in RGB order
+        let frame = [rgb.red, rgb.green, rgb.blue].map(|c| (c.clamp(0., 1.) * 255.).round() as u8);
+
+        if let Some((sent_at, last_frame)) = self.last_frame {
+            if last_frame == frame {
+                self.pending_frame = None;
+                return Ok(());
+            }
+
+            // a burst's last frame is what the light ends up showing, so it's held back instead of dropped
+            if sent_at.elapsed() < UDP_SINK_MIN_INTERVAL {
+                self.pending_frame = Some(frame);
+                return Ok(());
+            }
+        }
+
+        self.send_now(frame).await
+    }
+
+    fn pending_frame_at(&self) -> Option<Instant> {
+        match (self.pending_frame, self.last_frame) {
+            (Some(_), Some((sent_at, _))) => Some(sent_at + UDP_SINK_MIN_INTERVAL),
+            _ => None,
+        }
+    }
+
+    async fn send_pending(&mut self) -> anyhow::Result<()> {
+        match self.pending_frame {
+            Some(frame) => self.send_now(frame).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send_now(&mut self, frame: [u8; 3]) -> anyhow::Result<()> {
+        self.pending_frame = None;
+        self.socket.send_to(&frame, self.target).await?;
+        self.last_frame = Some((Instant::now(), frame));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn received(socket: &UdpSocket) -> Option<[u8; 3]> {
+        let mut frame = [0; 3];
+        match tokio::time::timeout(Duration::from_millis(50), socket.recv(&mut frame)).await {
+            Ok(result) => {
+                result.expect("failed to receive frame");
+                Some(frame)
+            }
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_sink_sends_the_last_frame_of_a_burst() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.expect("failed to bind");
+        let mut sink = UdpSink::new(receiver.local_addr().expect("no address"))
+            .await
+            .expect("failed to create sink");
+
+        sink.send(Rgb::new(1., 0., 0.)).await.expect("failed to send");
+        sink.send(Rgb::new(0., 1., 0.)).await.expect("failed to send");
+        sink.send(Rgb::new(0., 0., 1.)).await.expect("failed to send");
+        assert_eq!(received(&receiver).await, Some([255, 0, 0]));
+        assert_eq!(received(&receiver).await, None);
+
+        let due = sink.pending_frame_at().expect("no frame held back");
+        tokio::time::sleep_until(due.into()).await;
+        sink.send_pending().await.expect("failed to send");
+        assert_eq!(received(&receiver).await, Some([0, 0, 255]));
+        assert_eq!(sink.pending_frame_at(), None);
+    }
+}
@@ -0,0 +1,91 @@
+use log::*;
+use serde::Deserialize;
+use tokio::time::{Duration, Instant};
+
+use crate::{config::Config, state::State, ControlMessage, OnState};
+
+/// What to do with the light when the room becomes empty
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceAction {
+    Off,
+    Dim,
+}
+
+/// Tracks the presence reported by a room-presence sensor and turns it into commands for the light once the reported
+/// presence has settled.
+#[derive(Debug, Default)]
+pub struct Presence {
+    occupied: Option<bool>,
+    pending: Option<(bool, Instant)>,
+    restore: Option<(OnState, u8)>,
+}
+
+impl Presence {
+    /// Processes a payload from the presence topic. A change in presence takes effect only after it's been stable for
+    /// the debounce duration, so a flapping sensor doesn't flap the light.
+    pub fn update(&mut self, payload: &[u8], config: &Config) {
+        let payload = String::from_utf8_lossy(payload);
+        let occupied = match payload.trim().to_ascii_lowercase().as_str() {
+            "on" | "true" | "1" | "occupied" | "home" | "detected" => true,
+            "off" | "false" | "0" | "empty" | "unoccupied" | "not_home" | "clear" => false,
+            _ => {
+                warn!("Ignoring unknown presence payload: {payload}");
+                return;
+            }
+        };
+
+        debug!("Presence reported as {occupied}");
+
+        self.pending = if self.occupied == Some(occupied) {
+            // the presence flapped back before it settled
+            None
+        } else {
+            let debounce = Duration::from_secs_f32(config.presence_debounce);
+            Some((occupied, Instant::now() + debounce))
+        };
+    }
+
+    /// When the pending presence change settles, if there is one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|(_, deadline)| deadline)
+    }
+
+    /// Settles the pending presence change and returns the command to apply for it, if any
+    pub fn settle(&mut self, state: &State, config: &Config) -> Option<ControlMessage> {
+        let (occupied, _) = self.pending.take()?;
+        let previous = self.occupied.replace(occupied);
+        info!("Presence settled to {occupied}");
+
+        // the very first report only establishes the presence. the light's state then is whatever it was restored to
+        previous?;
+
+        if occupied {
+            let (state, brightness) = self.restore.take()?;
+
+            Some(ControlMessage {
+                state: Some(state),
+                brightness: Some(brightness),
+                ..Default::default()
+            })
+        } else {
+            self.restore = Some((state.state, state.brightness));
+
+            // dimming a light that's off would turn it on
+            if config.presence_empty_action == PresenceAction::Dim && state.state == OnState::Off {
+                return None;
+            }
+
+            Some(match config.presence_empty_action {
+                PresenceAction::Off => ControlMessage {
+                    state: Some(OnState::Off),
+                    ..Default::default()
+                },
+                PresenceAction::Dim => ControlMessage {
+                    brightness: Some(state.brightness.min(config.presence_dim_brightness)),
+                    ..Default::default()
+                },
+            })
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use chrono::{Days, Local, NaiveDateTime, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, ControlMessage, OnState};
+
+/// A command to be applied once at a local time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledCommand {
+    pub at: NaiveDateTime,
+    pub command: ControlMessage,
+}
+
+/// The current local time
+pub fn now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+/// The next time it's the given local time of day, today if it's still ahead and otherwise tomorrow
+pub fn next_daily(time: NaiveTime) -> NaiveDateTime {
+    let now = now();
+    let today = now.date().and_time(time);
+
+    if today > now {
+        today
+    } else {
+        today.checked_add_days(Days::new(1)).expect("date out of range")
+    }
+}
+
+/// The next of the daily times the light is turned on or off at, if either is configured, and the state the light is
+/// turned to then
+pub fn next_daily_switch(config: &Config) -> Option<(NaiveDateTime, OnState)> {
+    [(config.on_time, OnState::On), (config.off_time, OnState::Off)]
+        .into_iter()
+        .filter_map(|(time, state)| time.map(|time| (next_daily(time), state)))
+        .min_by_key(|(at, _)| *at)
+}
+
+/// The brightness the light is capped at, if it's currently the night
+pub fn night_brightness(config: &Config) -> Option<u8> {
+    let (start, end) = config.night_start.zip(config.night_end)?;
+    let time = now().time();
+
+    // a night going past midnight is the time after its start or before its end
+    let is_night = if start <= end {
+        start <= time && time < end
+    } else {
+        start <= time || time < end
+    };
+
+    is_night.then_some(config.night_brightness)
+}
+
+/// The next time the night starts or ends at, if it's configured, and the brightness cap from then on
+pub fn next_night_change(config: &Config) -> Option<(NaiveDateTime, Option<u8>)> {
+    let (start, end) = config.night_start.zip(config.night_end)?;
+
+    [(start, Some(config.night_brightness)), (end, None)]
+        .into_iter()
+        .map(|(time, cap)| (next_daily(time), cap))
+        .min_by_key(|(at, _)| *at)
+}
+
+/// Waits until the given local time, or forever without one
+pub async fn sleep_until(at: Option<NaiveDateTime>) {
+    match at {
+        Some(at) => tokio::time::sleep(until(at)).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// How long it is until the given local time, or zero if it has passed
+pub fn until(at: NaiveDateTime) -> Duration {
+    // a local time may be ambiguous or not exist at all around DST changes. take the earlier of ambiguous times, and
+    // treat a nonexistent time as due
+    Local
+        .from_local_datetime(&at)
+        .earliest()
+        .and_then(|at| (at - Local::now()).to_std().ok())
+        .unwrap_or(Duration::ZERO)
+}
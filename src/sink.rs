@@ -0,0 +1,414 @@
+use std::{
+    fmt::Debug,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use log::*;
+use palette::rgb::Rgb;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    time,
+};
+
+use crate::config::{Config, LightSinkKind};
+
+// the range of how long to wait before opening the blaster again after failing to. the wait doubles on every
+// consecutive failure
+const MIN_BLASTER_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BLASTER_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+// how long to wait for a freshly exported PWM channel's files to become writable
+const PWM_EXPORT_ATTEMPTS: u32 = 10;
+const PWM_EXPORT_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+// every WS2812 data bit is sent as three SPI bits, a 1 as 110 and a 0 as 100, so at 2.4 MHz each of them takes the
+// 1.25 µs the strip expects
+const WS2812_SPI_SPEED_HZ: u32 = 2_400_000;
+const WS2812_SPI_BITS_PER_BIT: usize = 3;
+// the strip latches the colours after the data line has been low for a while. Newer strips need up to 280 µs
+const WS2812_RESET_BYTES: usize = 90;
+// _IOW('k', 4, u32) from linux/spi/spidev.h
+const SPI_IOC_WR_MAX_SPEED_HZ: libc::c_ulong = 0x4004_6b04;
+
+/// Something driving the light's channels with the duty cycles of a computed colour
+pub trait LightSink: Debug {
+    /// Writes the colour channels' duty cycles, between 0 and 1, and the white channel's if the light has one
+    fn write<'a>(&'a mut self, rgb: Rgb, white: Option<f32>, config: &'a Config) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// Returns the sink the config selects
+pub fn from_config(config: &Config) -> Box<dyn LightSink> {
+    if config.dry_run {
+        info!("Dry run, logging the light's output instead of writing it");
+        return Box::<DryRun>::default();
+    }
+
+    match config.light_sink {
+        LightSinkKind::Blaster => Box::<Blaster>::default(),
+        LightSinkKind::Pwm => Box::new(Pwm {
+            chip: config.pwm_chip.clone(),
+            ready: false,
+        }),
+        LightSinkKind::Ws2812 => Box::<Ws2812>::default(),
+    }
+}
+
+/// Logs the output instead of writing it anywhere, for running the light without its hardware
+#[derive(Debug, Default)]
+struct DryRun {
+    /// The last output logged, so an animation holding still doesn't flood the log
+    last: Option<(Rgb, Option<f32>)>,
+}
+
+impl LightSink for DryRun {
+    fn write<'a>(&'a mut self, rgb: Rgb, white: Option<f32>, _config: &'a Config) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            if self.last != Some((rgb, white)) {
+                match white {
+                    Some(white) => info!("Dry run: {} {} {} {white}", rgb.red, rgb.green, rgb.blue),
+                    None => info!("Dry run: {} {} {}", rgb.red, rgb.green, rgb.blue),
+                }
+
+                self.last = Some((rgb, white));
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// A write's colour channels' and white channel's duty cycles
+#[cfg(test)]
+pub type Write = (Rgb, Option<f32>);
+
+/// Records the output written to it, or fails every write, for testing what's written to the light
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub struct Recording {
+    /// Every write's duty cycles, shared with the clones handed out before the sink is boxed up
+    pub writes: std::sync::Arc<std::sync::Mutex<Vec<Write>>>,
+    pub fail: bool,
+}
+
+#[cfg(test)]
+impl LightSink for Recording {
+    fn write<'a>(&'a mut self, rgb: Rgb, white: Option<f32>, _config: &'a Config) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            anyhow::ensure!(!self.fail, "the light failed to write");
+            self.writes.lock().expect("poisoned writes").push((rgb, white));
+            Ok(())
+        })
+    }
+}
+
+/// Writes to pi-blaster's FIFO
+#[derive(Debug, Default)]
+struct Blaster {
+    /// The blaster kept open between writes, since animations write to it every step
+    file: Option<File>,
+    /// While the blaster can't be opened, how long to wait between the attempts to open it and when the next one is
+    unavailable: Option<(Duration, Instant)>,
+}
+
+impl LightSink for Blaster {
+    fn write<'a>(&'a mut self, rgb: Rgb, white: Option<f32>, config: &'a Config) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let white = match (config.pin_w, white) {
+                (Some(pin_w), Some(white)) => format!(" {pin_w}={white}"),
+                _ => String::new(),
+            };
+
+            let msg = format!(
+                "{pin_r}={r} {pin_g}={g} {pin_b}={b}{white}\n",
+                pin_r = config.pin_r,
+                pin_g = config.pin_g,
+                pin_b = config.pin_b,
+                r = rgb.red,
+                g = rgb.green,
+                b = rgb.blue
+            );
+
+            debug!("Writing to blaster: \"{}\"", &msg[..msg.len() - 1]);
+            self.write_msg(msg.as_bytes(), config).await
+        })
+    }
+}
+
+impl Blaster {
+    /// Writes a message to the blaster, reusing the blaster opened for an earlier write. A blaster that's unavailable
+    /// is most likely a daemon that's not running (yet). The light can't show anything until it is, but there's nothing
+    /// wrong with the light itself, so that's only logged once. The writes in the meantime are dropped, and opening it
+    /// is retried with a backoff so the writes every step don't keep trying. The first write after it's opened shows
+    /// the light again
+    async fn write_msg(&mut self, msg: &[u8], config: &Config) -> anyhow::Result<()> {
+        // a failed write with a blaster kept open may be because it's gone stale, e.g. the daemon restarted and
+        // recreated its FIFO, so it's opened again to try once more
+        if let Some(blaster) = &mut self.file {
+            match write_fully(blaster, msg).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    debug!("Writing to the open blaster failed, opening it again: {e}");
+                    self.file = None;
+                }
+            }
+        }
+
+        if let Some((_, retry_at)) = self.unavailable {
+            if Instant::now() < retry_at {
+                return Ok(());
+            }
+        }
+
+        let opened = OpenOptions::new()
+            .read(false)
+            .write(true)
+            .create(false)
+            .open(&config.blaster)
+            .await;
+
+        match opened {
+            Ok(mut blaster) => {
+                if self.unavailable.take().is_some() {
+                    info!("Blaster {} available again", config.blaster.display());
+                }
+
+                write_fully(&mut blaster, msg).await?;
+                self.file = Some(blaster);
+            }
+            Err(e) => {
+                let backoff = match self.unavailable {
+                    Some((backoff, _)) => {
+                        debug!("Blaster {} still unavailable: {e}", config.blaster.display());
+                        (backoff * 2).min(MAX_BLASTER_RETRY_BACKOFF)
+                    }
+                    None => {
+                        warn!(
+                            "Blaster {} unavailable, not writing to it until it can be opened: {e}",
+                            config.blaster.display()
+                        );
+                        MIN_BLASTER_RETRY_BACKOFF
+                    }
+                };
+
+                self.unavailable = Some((backoff, Instant::now() + backoff));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the whole message and flushes it, so it's in the blaster's FIFO by the time this returns instead of sitting
+/// in the file's buffer
+async fn write_fully(blaster: &mut File, msg: &[u8]) -> std::io::Result<()> {
+    blaster.write_all(msg).await?;
+    blaster.flush().await
+}
+
+/// Writes to the kernel's sysfs PWM interface. The pins are the PWM chip's channels instead of GPIO pins. The channels'
+/// rising edges can't be staggered to spread out their current draw: a sysfs channel only has a period, a duty cycle,
+/// a polarity and whether it's enabled, with no phase or offset, and the Pi's PWM channels share one clock and start
+/// their periods together
+#[derive(Debug)]
+struct Pwm {
+    chip: PathBuf,
+    /// Whether the channels have been exported and enabled
+    ready: bool,
+}
+
+impl LightSink for Pwm {
+    fn write<'a>(&'a mut self, rgb: Rgb, white: Option<f32>, config: &'a Config) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            // the setup is retried on every write until it succeeds, in case the chip only appears later
+            if !self.ready {
+                for channel in pwm_channels(config) {
+                    self.set_up_channel(channel, config.pwm_period).await?;
+                }
+
+                self.ready = true;
+            }
+
+            let duties = [
+                Some((config.pin_r, rgb.red)),
+                Some((config.pin_g, rgb.green)),
+                Some((config.pin_b, rgb.blue)),
+                config.pin_w.zip(white),
+            ];
+
+            debug!("Writing to PWM chip {}: {duties:?}", self.chip.display());
+
+            for (channel, duty) in duties.into_iter().flatten() {
+                let duty_cycle = (duty.clamp(0.0, 1.0) * config.pwm_period as f32).round() as u32;
+
+                if let Err(e) = fs::write(self.channel_path(channel).join("duty_cycle"), duty_cycle.to_string()).await {
+                    // the channel may have been unexported behind the light's back, so it's set up again on the next
+                    // write
+                    self.ready = false;
+                    return Err(e.into());
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Pwm {
+    fn channel_path(&self, channel: u8) -> PathBuf {
+        self.chip.join(format!("pwm{channel}"))
+    }
+
+    /// Exports a channel if it isn't yet, and enables it with the configured period
+    async fn set_up_channel(&self, channel: u8, period: u32) -> anyhow::Result<()> {
+        let path = self.channel_path(channel);
+
+        if !fs::try_exists(&path).await? {
+            debug!("Exporting PWM channel {channel} of {}", self.chip.display());
+            fs::write(self.chip.join("export"), channel.to_string()).await?;
+        }
+
+        // the channel's files may take a moment to appear or become writable after exporting it, e.g. while udev
+        // adjusts their permissions. The duty cycle can't be longer than the period, so it's cleared first in case
+        // the channel was left with a longer one
+        let mut attempt = 1;
+        while let Err(e) = reset_pwm_channel(&path, period).await {
+            if attempt >= PWM_EXPORT_ATTEMPTS {
+                return Err(e.context(format!(
+                    "failed to set up PWM channel {channel} of {}",
+                    self.chip.display()
+                )));
+            }
+
+            attempt += 1;
+            time::sleep(PWM_EXPORT_RETRY_DELAY).await;
+        }
+
+        write_pwm_attribute(&path, "enable", 1).await
+    }
+}
+
+async fn reset_pwm_channel(channel: &Path, period: u32) -> anyhow::Result<()> {
+    write_pwm_attribute(channel, "duty_cycle", 0).await?;
+    write_pwm_attribute(channel, "period", period).await
+}
+
+async fn write_pwm_attribute(channel: &Path, attribute: &str, value: u32) -> anyhow::Result<()> {
+    Ok(fs::write(channel.join(attribute), value.to_string()).await?)
+}
+
+fn pwm_channels(config: &Config) -> impl Iterator<Item = u8> {
+    [Some(config.pin_r), Some(config.pin_g), Some(config.pin_b), config.pin_w]
+        .into_iter()
+        .flatten()
+}
+
+/// Writes to a WS2812 strip through spidev, by encoding the strip's data signal as SPI data
+#[derive(Debug, Default)]
+struct Ws2812 {
+    /// The SPI device kept open between writes, like the blaster
+    spi: Option<File>,
+}
+
+impl LightSink for Ws2812 {
+    fn write<'a>(&'a mut self, rgb: Rgb, _white: Option<f32>, config: &'a Config) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let leds = config.ws2812_leds.unwrap_or_default() as usize;
+            let [r, g, b] = [rgb.red, rgb.green, rgb.blue].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            debug!(
+                "Writing to WS2812 strip {}: {r} {g} {b}",
+                config.ws2812_device.display()
+            );
+
+            let spi = match &mut self.spi {
+                Some(spi) => spi,
+                None => self.spi.insert(open_spi(config)?),
+            };
+
+            let msg = encode_ws2812([r, g, b], leds);
+            if let Err(e) = write_fully(spi, &msg).await {
+                self.spi = None;
+                return Err(e.into());
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Opens the SPI device and sets its speed to the one the strip's signal is encoded for
+fn open_spi(config: &Config) -> anyhow::Result<File> {
+    let spi = std::fs::OpenOptions::new().write(true).open(&config.ws2812_device)?;
+
+    // SAFETY: the file descriptor is the open SPI device's, and the ioctl only reads the speed it's given
+    let result = unsafe { libc::ioctl(spi.as_raw_fd(), SPI_IOC_WR_MAX_SPEED_HZ, &WS2812_SPI_SPEED_HZ) };
+    if result < 0 {
+        return Err(anyhow::Error::from(std::io::Error::last_os_error())
+            .context(format!("failed to set the speed of {}", config.ws2812_device.display())));
+    }
+
+    Ok(File::from_std(spi))
+}
+
+/// Encodes every LED showing the same colour as SPI data, followed by the low period latching the colours
+fn encode_ws2812([r, g, b]: [u8; 3], leds: usize) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(leds * 3 * WS2812_SPI_BITS_PER_BIT + WS2812_RESET_BYTES);
+
+    // the strip takes the colour in GRB order, each channel most significant bit first
+    let bits = u32::from_be_bytes([0, g, r, b]);
+    let mut spi_bits = 0u128;
+    for i in (0..24).rev() {
+        let bit = if bits >> i & 1 == 1 { 0b110 } else { 0b100 };
+        spi_bits = spi_bits << WS2812_SPI_BITS_PER_BIT | bit;
+    }
+    let spi_bytes = spi_bits.to_be_bytes();
+    let led = &spi_bytes[spi_bytes.len() - 3 * WS2812_SPI_BITS_PER_BIT..];
+
+    for _ in 0..leds {
+        encoded.extend_from_slice(led);
+    }
+
+    encoded.resize(encoded.len() + WS2812_RESET_BYTES, 0);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn blaster_retries_with_backoff_while_unavailable() {
+        let path = std::env::temp_dir().join(format!("moodlight-test-blaster-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config::for_tests(&[("BLASTER", path.to_str().expect("non-UTF8 path"))]);
+        let mut blaster = Blaster::default();
+
+        blaster.write_msg(b"first\n", &config).await.expect("write failed");
+        let (backoff, retry_at) = blaster.unavailable.expect("blaster not unavailable");
+        assert_eq!(backoff, MIN_BLASTER_RETRY_BACKOFF);
+
+        // the writes before the retry is due don't try to open it
+        blaster.write_msg(b"dropped\n", &config).await.expect("write failed");
+        assert_eq!(blaster.unavailable, Some((backoff, retry_at)));
+
+        time::sleep_until(retry_at.into()).await;
+        blaster.write_msg(b"dropped\n", &config).await.expect("write failed");
+        let (backoff, retry_at) = blaster.unavailable.expect("blaster not unavailable");
+        assert_eq!(backoff, MIN_BLASTER_RETRY_BACKOFF * 2);
+
+        std::fs::write(&path, "").expect("failed to create blaster");
+        time::sleep_until(retry_at.into()).await;
+        blaster.write_msg(b"shown\n", &config).await.expect("write failed");
+        assert!(blaster.unavailable.is_none());
+        assert!(blaster.file.is_some());
+        assert_eq!(
+            std::fs::read_to_string(&path).expect("failed to read blaster"),
+            "shown\n"
+        );
+
+        std::fs::remove_file(&path).expect("failed to remove blaster");
+    }
+}
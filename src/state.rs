@@ -1,12 +1,22 @@
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    time::{Duration, Instant},
+};
 
+use chrono::NaiveDateTime;
 use log::*;
-use palette::{encoding, rgb::Rgb, FromColor, Hsv};
-use rumqttc::v5::{mqttbytes::QoS, AsyncClient};
-use serde::{de::Visitor, Deserialize, Serialize};
-use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use palette::{encoding, rgb::Rgb, FromColor, Hsv, Mix, Srgb};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rumqttc::v5::AsyncClient;
+use serde::{Deserialize, Serialize};
 
-use crate::{Color, Config, ControlMessage, OnState};
+use crate::{
+    config::{Channel, RainbowColorPolicy},
+    output::Output,
+    schedule::ScheduledCommand,
+    state_file, Color, Config, ControlMessage, OnState,
+};
 
 const MIN_RAINBOW_SPEED_S: f32 = 1.0;
 const MAX_RAINBOW_SPEED_S: f32 = 60.0;
@@ -17,231 +27,2144 @@ pub const MAX_RAINBOW_SPEED_SETTING: f32 = 100.0; // the min is always assumed t
 // ranges are "inversed", the slope is negative
 const RAINBOW_SPEED_SLOPE: f32 = (MIN_RAINBOW_SPEED_S - MAX_RAINBOW_SPEED_S) / MAX_RAINBOW_SPEED_SETTING;
 
-const TRANSITION_LENGTH_S: f32 = 0.5;
+// the version of the serialised state's schema. bump it when the schema changes in a way that old states have to be
+// migrated, and handle the migration in State::migrate
+const STATE_VERSION: u32 = 1;
+
+// the length of the acknowledgement dip, and how far below the brightness it dips at its deepest
+const ACK_LENGTH_S: f32 = 1.5;
+const ACK_DEPTH: f32 = 0.6;
+
+// how long one blink of a flash takes, half of it lit and half black, and how long Home Assistant's short and long
+// flashes last in seconds
+const FLASH_BLINK_S: f32 = 0.5;
+pub const FLASH_SHORT_S: u32 = 2;
+pub const FLASH_LONG_S: u32 = 10;
+
+// the range of strobe frequencies in Hz, and the default one
+const MIN_STROBE_HZ: f32 = 0.5;
+const MAX_STROBE_HZ: f32 = 20.;
+const DEFAULT_STROBE_HZ: f32 = 5.;
+
+// the fraction of each palette colour's share of the cycle it's held for before moving on to the next colour
+const PALETTE_HOLD: f32 = 0.25;
+
+// the range of colour temperatures in mireds, which are Home Assistant's defaults
+pub const MIN_MIREDS: u16 = 153;
+pub const MAX_MIREDS: u16 = 500;
+
+// the fire's hue and how far it jitters either way around it, the range of its brightness as a fraction of the
+// commanded brightness, and the time constant in seconds its flicker is smoothed with
+const FIRE_HUE: f32 = 25.;
+const FIRE_HUE_JITTER: f32 = 8.;
+const FIRE_MIN_BRIGHTNESS: f32 = 0.45;
+const FIRE_SMOOTHING_S: f32 = 0.12;
+
+// how often the hex colour is published at most while the colour is animated
+const HEX_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
     Static,
     Rainbow,
+    Pulse,
+    Breathing,
+    Strobe,
+    Palette,
+    Fire,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 7] = [
+        Mode::Static,
+        Mode::Rainbow,
+        Mode::Pulse,
+        Mode::Breathing,
+        Mode::Strobe,
+        Mode::Palette,
+        Mode::Fire,
+    ];
+
+    /// The mode's name, as it is serialised
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Static => "Static",
+            Mode::Rainbow => "Rainbow",
+            Mode::Pulse => "Pulse",
+            Mode::Breathing => "Breathing",
+            Mode::Strobe => "Strobe",
+            Mode::Palette => "Palette",
+            Mode::Fire => "Fire",
+        }
+    }
+
+    /// The mode after this one in the order of [`Mode::ALL`], wrapping around from the last to the first
+    pub fn next(&self) -> Mode {
+        let index = Mode::ALL
+            .iter()
+            .position(|mode| mode == self)
+            .expect("mode missing from all modes");
+        Mode::ALL[(index + 1) % Mode::ALL.len()]
+    }
+
+    /// Whether the light's output changes on its own in this mode
+    pub fn is_animated(&self) -> bool {
+        match self {
+            Mode::Static => false,
+            Mode::Rainbow | Mode::Pulse | Mode::Breathing | Mode::Strobe | Mode::Palette | Mode::Fire => true,
+        }
+    }
+}
+
+/// Settings specific to a mode that are remembered while the light is in another mode.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy)]
+pub struct ModeParameters {
+    /// The colour last used in the static mode. Other modes may change the shared colour (the rainbow sweeps it), so
+    /// it's stored here when leaving the static mode and restored when returning to it.
+    #[serde(default)]
+    pub static_color: Option<Color>,
+    /// The saturation sweep along with the rainbow's hue, if any
+    #[serde(default)]
+    pub rainbow_saturation: Option<SaturationSweep>,
+    /// How many times a second the strobe flashes
+    #[serde(default)]
+    pub strobe_hz: Option<f32>,
+    /// The part of the colour wheel the rainbow stays within, if not all of it
+    #[serde(default)]
+    pub rainbow_range: Option<HueRange>,
+    /// Whether the rainbow goes around the colour wheel backwards
+    #[serde(default)]
+    pub rainbow_reverse: bool,
+}
+
+/// A part of the colour wheel from the start hue forwards to the end hue, in degrees. The rainbow bounces back and
+/// forth within it, so a rainbow cycle goes there and back.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct HueRange {
+    pub start: f32,
+    pub end: f32,
+}
+
+/// A sweep of the saturation between two values which follows the rainbow's hue, so a rainbow cycle is also one cycle
+/// of the saturation. The phase offsets the saturation's cycle from the hue, in degrees. When the hue is at the
+/// phase the saturation is at its maximum, and when it's opposite the phase the saturation is at its minimum.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct SaturationSweep {
+    pub min: f32,
+    pub max: f32,
+    #[serde(default)]
+    pub phase: f32,
+}
+
+impl SaturationSweep {
+    fn saturation(&self, hue: f32) -> f32 {
+        let middle = (self.min + self.max) / 2.;
+        let amplitude = (self.max - self.min) / 2.;
+        middle + amplitude * (hue - self.phase).to_radians().cos()
+    }
+}
+
+/// How the colour was last set, as Home Assistant's colour modes
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ColorMode {
+    #[default]
+    Hs,
+    ColorTemp,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportedRgb {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+/// The state as it's published, with fields that are only reported
+#[derive(Debug, Serialize)]
+struct PublishedState<'a> {
+    #[serde(flatten)]
+    state: &'a State,
+    rgb: ReportedRgb,
+    /// The mode as an effect of Home Assistant's JSON light schema
+    effect: &'a str,
 }
 
+/// A one-shot flash shown over whatever the light is showing, as Home Assistant's short or long flash or as a number
+/// of blinks in an optional colour other than the light's own
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum Flash {
+    Length(FlashLength),
+    Blinks {
+        times: u32,
+        #[serde(default)]
+        color: Option<Color>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FlashLength {
+    Short,
+    Long,
+}
+
+/// A flash in progress. The state underneath it is left alone, so the light returns to it once the flash is over
+#[derive(Debug, Clone, Copy)]
+struct ActiveFlash {
+    elapsed: f32,
+    length: f32,
+    color: Option<Color>,
+}
+
+impl ActiveFlash {
+    fn new(flash: Flash) -> Self {
+        let (length, color) = match flash {
+            Flash::Length(FlashLength::Short) => (FLASH_SHORT_S as f32, None),
+            Flash::Length(FlashLength::Long) => (FLASH_LONG_S as f32, None),
+            Flash::Blinks { times, color } => (times as f32 * FLASH_BLINK_S, color),
+        };
+
+        Self {
+            elapsed: 0.,
+            length,
+            color,
+        }
+    }
+
+    /// Whether the flash is in the lit half of a blink
+    fn is_lit(&self) -> bool {
+        self.elapsed % FLASH_BLINK_S < FLASH_BLINK_S / 2.
+    }
+}
+
+/// The fire's smoothed flicker. The brightness is a fraction of the commanded brightness, and the hue offset is from
+/// the fire's hue
+#[derive(Debug, Clone, Copy)]
+struct Flicker {
+    brightness: f32,
+    hue_offset: f32,
+}
+
+impl Default for Flicker {
+    fn default() -> Self {
+        Self {
+            brightness: 1.,
+            hue_offset: 0.,
+        }
+    }
+}
+
+/// How a transition's brightness follows its progress
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps a transition's progress between 0 and 1 to how far the brightness has got, which is also between 0 and 1
+    fn apply(&self, progress: f32) -> f32 {
+        match self {
+            Easing::Linear => progress,
+            Easing::EaseIn => progress * progress,
+            Easing::EaseOut => 1. - (1. - progress) * (1. - progress),
+            Easing::EaseInOut if progress < 0.5 => 2. * progress * progress,
+            Easing::EaseInOut => 1. - (2. - 2. * progress).powi(2) / 2.,
+        }
+    }
+}
+
+/// How a transition's colour gets from where it starts to its target
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorInterpolation {
+    /// Around the colour wheel the shorter way, keeping the colours vivid but passing through the hues in between
+    #[default]
+    Hsv,
+    /// In a straight line in linear RGB, which doesn't pass through other hues but dims and desaturates on the way
+    /// between distant colours
+    LinearRgb,
+}
+
+/// Where the animated modes have got to. It's stored with the state so the animations continue where they left off
+/// after a restart. A mode that needs more than the phase gets a field of its own here, which states stored before it
+/// existed don't have and take the default for
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+struct Animation {
+    /// How far into its cycle the mode's animation is, between 0 and 1
+    phase: f32,
+}
+
+/// A transition to be started when the state is next applied
+#[derive(Debug, Clone, Copy)]
+struct PendingTransition {
+    /// How long the transition takes in seconds
+    length: f32,
+    from_brightness: f32,
+    /// Whether the brightness fades, or it snaps to the target brightness right away
+    fade_brightness: bool,
+    /// The colour the transition starts from, if the colour changes and fades
+    from_color: Option<Color>,
+    easing: Easing,
+    interpolation: ColorInterpolation,
+}
+
+/// A brightness fade, and possibly a colour change, in progress, stepped along with the other animations
+#[derive(Debug, Clone, Copy)]
+struct Transition {
+    /// The brightness the transition started from
+    from: f32,
+    /// The colour the transition started from, if the colour changes
+    from_color: Option<Color>,
+    /// How far the transition has got, between 0 and 1
+    progress: f32,
+    progress_step: f32,
+    easing: Easing,
+    interpolation: ColorInterpolation,
+}
+
+impl Transition {
+    /// The brightness the transition has got to on its way to the target brightness
+    fn brightness(&self, target: f32) -> f32 {
+        self.from + (target - self.from) * self.easing.apply(self.progress)
+    }
+
+    /// The colour the transition has got to on its way to the target colour, and the value between 0 and 1 it's at on
+    /// the way. The value is below 1 only in linear RGB, where the way between two colours is below the full value
+    fn color(&self, target: Color) -> (Color, f32) {
+        let Some(from) = self.from_color else {
+            return (target, 1.);
+        };
+
+        let progress = self.easing.apply(self.progress);
+
+        match self.interpolation {
+            ColorInterpolation::Hsv => {
+                let hue_change = (target.h - from.h + 180.).rem_euclid(360.) - 180.;
+                let color = Color {
+                    h: (from.h + hue_change * progress).rem_euclid(360.),
+                    s: from.s + (target.s - from.s) * progress,
+                };
+
+                (color, 1.)
+            }
+            ColorInterpolation::LinearRgb => {
+                let linear = |color: Color| Srgb::from_color(Hsv::new(color.h, color.s / 100., 1.)).into_linear();
+                let mixed = Hsv::from_color(Srgb::from_linear(linear(from).mix(linear(target), progress)));
+                let color = Color {
+                    h: mixed.hue.into_positive_degrees(),
+                    s: mixed.saturation * 100.,
+                };
+
+                (color, mixed.value)
+            }
+        }
+    }
+}
+
+/// A state edited by a command, along with what of the command was left out of it
 #[derive(Debug)]
-struct HsColorMode;
+pub struct Edited {
+    pub state: State,
+    /// The commanded colour, if the rainbow colour policy discarded it
+    pub ignored_color: Option<Color>,
+}
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The light's state. It's stored as a retained message so it can be restored on startup, and since that message may
+/// have been stored by an older version, deserialising it takes any missing fields from the default state and ignores
+/// unknown fields.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct State {
+    /// The schema version the state was serialised with. States from before versioning don't have it and are version 0
+    #[serde(default)]
+    version: u32,
     pub color: Color,
     pub brightness: u8,
     pub rainbow_speed: f32,
     pub mode: Mode,
     pub state: OnState,
+    pub mode_params: ModeParameters,
+    /// One-shot commands scheduled for later, stored with the state so they survive restarts
+    pub scheduled: Vec<ScheduledCommand>,
 
-    color_mode: HsColorMode,
+    color_mode: ColorMode,
+    /// The colour temperature in mireds the colour was set from, in the colour temperature mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color_temp: Option<u16>,
+    animation: Animation,
+    #[serde(skip)]
+    pending_transition: Option<PendingTransition>,
+    #[serde(skip)]
+    transition: Option<Transition>,
+    #[serde(skip)]
+    queued_commands: VecDeque<ControlMessage>,
+    #[serde(skip)]
+    published_hex: Option<(Instant, String)>,
+    /// The state last published to the state topic, to recognise it when it's echoed back without no-local
+    #[serde(skip)]
+    published_state: Option<Vec<u8>>,
+    /// When the state was last published, and whether a publish has been held back since to not publish too often
+    #[serde(skip)]
+    state_published_at: Option<Instant>,
+    #[serde(skip)]
+    publish_pending: bool,
+    /// The priority of the last prioritised command and when its hold ends
+    #[serde(skip)]
+    hold: Option<(u8, Instant)>,
+    /// When the light was last changed by a command sent to it, as opposed to by one of its automations
+    #[serde(skip)]
+    manual_change: Option<Instant>,
+    /// How far into the acknowledgement dip the light is, in seconds
+    #[serde(skip)]
+    ack: Option<f32>,
+    #[serde(skip)]
+    flash: Option<ActiveFlash>,
+    /// What the brightness shown is capped at during the night. It caps what's shown instead of the brightness itself,
+    /// so the commanded brightness is shown again once the night is over
+    #[serde(skip)]
+    night_cap: Option<u8>,
+    /// When the strobe started flashing. The flashes are timed from it instead of counting steps, so they keep their
+    /// rate even if the steps run late
+    #[serde(skip)]
+    strobe_started: Option<Instant>,
+    /// Where the fire's flicker has got to
     #[serde(skip)]
-    transition: bool,
+    flicker: Flicker,
+    #[serde(skip)]
+    rng: SmallRng,
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
+            version: STATE_VERSION,
             color: Color { h: 360.0, s: 100.0 },
             brightness: u8::MAX,
             rainbow_speed: MAX_RAINBOW_SPEED_S,
             mode: Mode::Static,
             state: OnState::Off,
+            mode_params: ModeParameters::default(),
+            scheduled: Vec::new(),
 
-            color_mode: HsColorMode,
-            transition: false,
+            color_mode: ColorMode::Hs,
+            color_temp: None,
+            animation: Animation::default(),
+            pending_transition: None,
+            transition: None,
+            queued_commands: VecDeque::new(),
+            published_hex: None,
+            published_state: None,
+            state_published_at: None,
+            publish_pending: false,
+            hold: None,
+            manual_change: None,
+            ack: None,
+            flash: None,
+            night_cap: None,
+            strobe_started: None,
+            flicker: Flicker::default(),
+            rng: SmallRng::from_entropy(),
         }
     }
 }
 
-impl Serialize for HsColorMode {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_str("hs")
+impl State {
+    /// Upgrades a deserialised state from an older schema version to the current one
+    pub fn migrate(&mut self) {
+        if self.version < STATE_VERSION {
+            // version 0 has the same fields as version 1, with any of them possibly missing and defaulted
+            info!("Migrating state from version {} to {STATE_VERSION}", self.version);
+            self.version = STATE_VERSION;
+        }
     }
-}
 
-impl<'de> Deserialize<'de> for HsColorMode {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        struct HsColorModeVisitor;
+    /// Whether a command with the given priority is held off by a command with a higher priority. Commands without a
+    /// priority have the lowest priority, 0, such as automations. Any command with a priority above 0, such as a
+    /// manual change, holds off commands with a lower priority for the configured hold period.
+    pub fn is_held(&self, priority: u8) -> bool {
+        self.is_held_at(priority, Instant::now())
+    }
 
-        impl<'de> Visitor<'de> for HsColorModeVisitor {
-            type Value = HsColorMode;
+    /// Whether a command with the given priority is held off at the given time
+    fn is_held_at(&self, priority: u8, now: Instant) -> bool {
+        self.hold
+            .is_some_and(|(hold_priority, until)| priority < hold_priority && now < until)
+    }
 
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("the string 'hs'")
-            }
+    pub fn mark_manual_change(&mut self) {
+        self.manual_change = Some(Instant::now());
+    }
 
-            fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                if v == "hs" {
-                    Ok(HsColorMode)
-                } else {
-                    Err(serde::de::Error::invalid_value(serde::de::Unexpected::Str(v), &self))
-                }
-            }
+    /// Whether the automations (presence and scheduled commands) are held off after a manual change. Unlike a
+    /// priority hold, which holds off lower-priority commands from any source, this holds off only the automations
+    /// regardless of their priority
+    pub fn is_manually_held(&self, config: &Config) -> bool {
+        self.is_manually_held_at(config, Instant::now())
+    }
+
+    /// Whether the automations are held off at the given time
+    fn is_manually_held_at(&self, config: &Config, now: Instant) -> bool {
+        match (config.manual_hold, self.manual_change) {
+            (Some(hold), Some(changed)) => now.saturating_duration_since(changed) < Duration::from_secs_f32(hold),
+            _ => false,
         }
+    }
 
-        deserializer.deserialize_str(HsColorModeVisitor)
+    pub fn edit(&mut self, msg: ControlMessage, config: &Config) {
+        let Edited { state, ignored_color } = self.edited(&msg, config, Instant::now());
+
+        if let Some(color) = ignored_color {
+            warn!("Ignoring commanded colour {color:?} since the light is in the rainbow mode");
+        }
+
+        *self = state;
     }
-}
 
-impl State {
-    pub fn edit(&mut self, msg: ControlMessage) {
-        *self = Self {
-            color: match (self.mode, msg.mode) {
-                // update the colour only if the current mode is static, or it's being set to static
-                (Mode::Static, _) | (_, Some(Mode::Static)) => msg.color.unwrap_or(self.color),
-                _ => self.color,
-            },
-            brightness: msg.brightness.unwrap_or(self.brightness),
+    /// Computes the state resulting from a command at the given time, without changing this state. A transition the
+    /// command starts is pending in the resulting state, to be started when it's applied.
+    pub fn edited(&self, msg: &ControlMessage, config: &Config, now: Instant) -> Edited {
+        let has_color = msg.color.is_some() || msg.color_temp.is_some();
+        let commanded_mode = match (msg.mode, msg.mode_next) {
+            (Some(mode), _) => Some(mode),
+            (None, Some(true)) => Some(self.mode.next()),
+            // pausing the rainbow at a colour is leaving it for the static mode with that colour
+            (None, _)
+                if self.mode == Mode::Rainbow && has_color && config.rainbow_color == RainbowColorPolicy::Pause =>
+            {
+                Some(Mode::Static)
+            }
+            (None, _) => None,
+        };
+
+        let mode = commanded_mode.unwrap_or(self.mode);
+        let mode_params = self.edited_mode_params(msg, mode);
+        let color_temp = msg.color_temp.map(|mireds| mireds.clamp(MIN_MIREDS, MAX_MIREDS));
+        let (color, ignored_color) = self.edited_color(
+            msg.color.or(color_temp.map(color_temp_to_color)),
+            commanded_mode,
+            &mode_params,
+            config,
+        );
+
+        // the modes sweeping the hue leave behind a colour that isn't any temperature
+        let (color_mode, color_temp) = match (color_temp, msg.color) {
+            _ if matches!(mode, Mode::Rainbow | Mode::Palette | Mode::Fire) => (ColorMode::Hs, None),
+            (_, Some(_)) => (ColorMode::Hs, None),
+            (Some(mireds), None) => (ColorMode::ColorTemp, Some(mireds)),
+            (None, None) => (self.color_mode, self.color_temp),
+        };
+        let state = self.edited_on_state(msg, config);
+        let brightness = msg.brightness.unwrap_or(self.brightness);
+
+        let state = Self {
+            version: self.version,
+            color,
+            brightness,
             rainbow_speed: msg
                 .rainbow_speed
                 .map(|s| s.clamp(0., MAX_RAINBOW_SPEED_SETTING))
                 .unwrap_or(self.rainbow_speed),
-            state: msg.state.unwrap_or(self.state),
-            mode: msg.mode.unwrap_or(self.mode),
+            state,
+            mode,
+            mode_params,
+            scheduled: self.scheduled.clone(),
+            pending_transition: self.edited_transition(msg, state, brightness, color, mode, config),
+            color_mode,
+            color_temp,
+            // breathing, the strobe and the palette start their cycles from the beginning instead of wherever a
+            // previous mode's cycle left off
+            animation: if matches!(mode, Mode::Breathing | Mode::Strobe | Mode::Palette) && self.mode != mode {
+                Animation::default()
+            } else {
+                self.animation
+            },
+            transition: self.transition,
+            queued_commands: self.queued_commands.clone(),
+            published_hex: self.published_hex.clone(),
+            published_state: self.published_state.clone(),
+            state_published_at: self.state_published_at,
+            publish_pending: self.publish_pending,
+            hold: match msg.priority {
+                Some(priority) if priority > 0 => Some((priority, now + Duration::from_secs_f32(config.priority_hold))),
+                _ => self.hold,
+            },
+            manual_change: self.manual_change,
+            // the dip only shows on top of whatever the light is showing, so there's nothing to acknowledge with
+            // while it's off
+            ack: match msg.ack {
+                Some(true) if state == OnState::On => Some(0.),
+                _ => self.ack,
+            },
+            // a new flash restarts any flash in progress
+            flash: msg.flash.map(ActiveFlash::new).or(self.flash),
+            night_cap: self.night_cap,
+            strobe_started: if mode == Mode::Strobe && self.mode != Mode::Strobe {
+                Some(now)
+            } else {
+                self.strobe_started
+            },
+            flicker: self.flicker,
+            rng: self.rng.clone(),
+        };
+
+        Edited { state, ignored_color }
+    }
+
+    /// Caps the brightness shown for the night, or lifts the cap. A change in what the light shows is transitioned to
+    /// over the given length once the state is applied. A colour transition in progress carries on from where it got
+    pub fn set_night_cap(&mut self, cap: Option<u8>, length: f32, config: &Config) {
+        let from_brightness = self.output_brightness();
+        let from_color = self
+            .transition
+            .and_then(|transition| transition.from_color)
+            .map(|_| self.output_color());
+        let target_brightness = self.target_brightness();
+
+        self.night_cap = cap;
+
+        if self.target_brightness() == target_brightness {
+            return;
+        }
+
+        self.pending_transition = Some(PendingTransition {
+            length,
+            from_brightness,
+            fade_brightness: true,
+            from_color,
+            easing: config.transition_easing,
+            interpolation: self
+                .transition
+                .map_or(config.color_interpolation, |transition| transition.interpolation),
+        });
+    }
+
+    fn edited_mode_params(&self, msg: &ControlMessage, mode: Mode) -> ModeParameters {
+        let mut mode_params = self.mode_params;
+
+        if self.mode == Mode::Static && mode != Mode::Static {
+            mode_params.static_color = Some(self.color);
+        }
+
+        if let Some(range) = msg.rainbow_range {
+            let (start, end) = (range.start.rem_euclid(360.), range.end.rem_euclid(360.));
+
+            // a range that ends where it starts goes all the way around
+            mode_params.rainbow_range = (start != end).then_some(HueRange { start, end });
+        }
 
-            transition: msg.state.map_or(false, |state| state != self.state),
-            color_mode: HsColorMode,
+        if let Some(reverse) = msg.rainbow_reverse {
+            mode_params.rainbow_reverse = reverse;
+        }
+
+        if let Some(hz) = msg.strobe_hz {
+            mode_params.strobe_hz = Some(hz.clamp(MIN_STROBE_HZ, MAX_STROBE_HZ));
+        }
+
+        if let Some(sweep) = msg.saturation_sweep {
+            let (min, max) = (sweep.min.clamp(0., 100.), sweep.max.clamp(0., 100.));
+
+            mode_params.rainbow_saturation = Some(SaturationSweep {
+                min: min.min(max),
+                max: min.max(max),
+                phase: sweep.phase.rem_euclid(360.),
+            });
+        }
+
+        mode_params
+    }
+
+    fn edited_color(
+        &self,
+        color: Option<Color>,
+        commanded_mode: Option<Mode>,
+        mode_params: &ModeParameters,
+        config: &Config,
+    ) -> (Color, Option<Color>) {
+        let mode = commanded_mode.unwrap_or(self.mode);
+        let mut color = match (self.mode, commanded_mode, color) {
+            // returning to the static mode without a colour restores the colour it was left with
+            (current, Some(Mode::Static), None) if current != Mode::Static => {
+                mode_params.static_color.unwrap_or(self.color)
+            }
+            (_, _, None) => self.color,
+            // the light is in the rainbow mode and stays there, so the colour would be overwritten by the sweep on
+            // the next step. the policy decides whether to jump the sweep to the colour or to discard it. pausing has
+            // already left the rainbow mode unless the command explicitly kept it, so it's the same as jumping
+            (Mode::Rainbow, None | Some(Mode::Rainbow), Some(color)) => match config.rainbow_color {
+                RainbowColorPolicy::Apply | RainbowColorPolicy::Pause => color,
+                RainbowColorPolicy::Ignore => return (self.color, Some(color)),
+            },
+            (_, _, Some(color)) => color,
         };
+
+        // restarting the rainbow from a fixed hue replaces any colour it would've otherwise started from
+        if self.mode != Mode::Rainbow && mode == Mode::Rainbow {
+            if let Some(hue) = config.rainbow_start_hue {
+                color.h = hue;
+            }
+        }
+
+        (color, None)
     }
 
-    pub async fn publish_to_mqtt(&self, client: &AsyncClient, state_topic: &str) -> anyhow::Result<()> {
-        let state_json = serde_json::to_vec(self).expect("failed to serialise state");
+    fn edited_transition(
+        &self,
+        msg: &ControlMessage,
+        state: OnState,
+        brightness: u8,
+        color: Color,
+        mode: Mode,
+        config: &Config,
+    ) -> Option<PendingTransition> {
+        // the modes sweeping the hue change the colour every step, so there's no colour for them to transition to.
+        // while the light's off there's no colour showing to transition from. leaving them cross-fades from wherever
+        // the sweep got to
+        let shown_color = self.swept_color(self.output_color());
+        let fade_brightness = msg.transition_brightness.unwrap_or(config.transition_brightness);
+        let color_changes = msg.transition_color.unwrap_or(config.transition_color)
+            && state == OnState::On
+            && self.state == OnState::On
+            && !matches!(mode, Mode::Rainbow | Mode::Palette | Mode::Fire)
+            && (color.h != shown_color.h || color.s != shown_color.s);
 
-        if let Err(e) = client.publish(state_topic, QoS::AtLeastOnce, true, state_json).await {
+        // a transition given in seconds or as a number of steps of the configured step duration overrides the default
+        // length, or the configured length for fading to black
+        let commanded_length = msg.transition_length(config);
+        let length = if state != self.state {
+            match (commanded_length, state) {
+                (Some(length), _) => length,
+                (None, OnState::Off) => config.off_transition_duration.unwrap_or(config.transition_duration),
+                (None, OnState::On) => config.transition_duration,
+            }
+        } else {
+            // the on state staying the same transitions a change in the colour by default, but a change in the
+            // brightness only when asked to. without either change fading the transition's ignored
+            let brightness_changes = fade_brightness && brightness != self.brightness;
+
+            match commanded_length {
+                Some(length) if state == OnState::On && (brightness_changes || color_changes) => length,
+                None if color_changes => config.transition_duration,
+                _ => return None,
+            }
+        };
+
+        Some(PendingTransition {
+            length: length.max(0.),
+            from_brightness: self.output_brightness(),
+            fade_brightness,
+            from_color: color_changes.then_some(shown_color),
+            easing: msg.easing.unwrap_or(config.transition_easing),
+            interpolation: msg.color_interpolation.unwrap_or(config.color_interpolation),
+        })
+    }
+
+    fn edited_on_state(&self, msg: &ControlMessage, config: &Config) -> OnState {
+        // like Home Assistant's lights, setting the brightness of a light that's off may turn it on
+        match msg.state {
+            Some(state) => state,
+            None if config.brightness_implies_on && msg.brightness.is_some() => OnState::On,
+            None => self.state,
+        }
+    }
+
+    /// The state as it's published. It includes the RGB values the light shows, for consumers that want to know the
+    /// actual output. They're only reported and ignored when the state is restored. Animated modes aren't published as
+    /// they step, so for them the values are only updated on commands
+    pub fn to_published_value(&self, config: &Config) -> serde_json::Value {
+        serde_json::to_value(PublishedState {
+            state: self,
+            rgb: self.settled_rgb(config),
+            effect: config.mode_label(self.mode),
+        })
+        .expect("failed to serialise state")
+    }
+
+    /// Publishes the state, unless it was published less than the configured interval ago. Then the publish is held
+    /// back until the interval is over, so a burst of changes is published as its last state
+    pub async fn publish_to_mqtt(
+        &mut self,
+        client: &AsyncClient,
+        state_topic: &str,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        if let (Some(interval), Some(published_at)) = (config.state_publish_interval, self.state_published_at) {
+            if published_at.elapsed() < Duration::from_secs_f32(interval) {
+                self.publish_pending = true;
+                return Ok(());
+            }
+        }
+
+        self.publish_to_mqtt_now(client, state_topic, config).await
+    }
+
+    /// When the publish held back is due, if there is one
+    pub fn pending_publish_at(&self, config: &Config) -> Option<Instant> {
+        match (
+            self.publish_pending,
+            config.state_publish_interval,
+            self.state_published_at,
+        ) {
+            (true, Some(interval), Some(published_at)) => Some(published_at + Duration::from_secs_f32(interval)),
+            _ => None,
+        }
+    }
+
+    /// Publishes the state right away, including any publish that's been held back
+    pub async fn publish_to_mqtt_now(
+        &mut self,
+        client: &AsyncClient,
+        state_topic: &str,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        self.publish_pending = false;
+        self.state_published_at = Some(Instant::now());
+
+        let state = self.to_published_value(config);
+        let state_json = serde_json::to_vec(&state).expect("failed to serialise state");
+
+        if let Err(e) = client
+            .publish(state_topic, config.state_qos, config.state_retain, state_json.clone())
+            .await
+        {
             error!("Failed to publish current state: {e}");
+        } else {
+            self.published_state = Some(state_json.clone());
+        }
+
+        if let Some(path) = &config.state_file {
+            state_file::save(path, &state_json).await;
+        }
+
+        // the state topic always has the state's own shape since it's what's restored on startup, and what Home
+        // Assistant reads. the templated shape goes to its own topic
+        if let Some(template) = &config.state_template {
+            let templated_json = serde_json::to_vec(&template.render(&state)).expect("failed to serialise state");
+
+            if let Err(e) = client
+                .publish(
+                    config.templated_state_topic(),
+                    config.state_qos,
+                    config.state_retain,
+                    templated_json,
+                )
+                .await
+            {
+                error!("Failed to publish current templated state: {e}");
+            }
         }
 
         Ok(())
     }
 
-    pub fn step_hue(&mut self, step_duration: f32) {
-        // the rainbow speed is a measure of how long it should take to go through all the colours, i.e. go through the
-        // 360 degrees of the colour wheel. the value is between 0 and 100 where 0 = slowest, i.e. longest time and 100
-        // = fastest, i.e. quickest time. the slope constant provides this mapping. by knowing how often the steps are
-        // taken, calculate how long each step should be to achieve the correct time
+    /// Publishes the current colour as `#RRGGBB` to the hex topic if it's enabled and the colour has changed since it
+    /// was last published. The colour is the logical colour: the hue, saturation and brightness the light is set to,
+    /// or black when it's off, before any output adjustments such as the power budget. When throttled, the colour is
+    /// published only if the previous publish was long enough ago.
+    pub async fn publish_hex(&mut self, client: &AsyncClient, config: &Config, throttle: bool) {
+        if !config.publish_hex {
+            return;
+        }
+
+        let hex = self.hex_color();
+
+        if let Some((published_at, published)) = &self.published_hex {
+            if *published == hex || (throttle && published_at.elapsed() < HEX_PUBLISH_INTERVAL) {
+                return;
+            }
+        }
+
+        if let Err(e) = client
+            .publish(config.hex_topic(), config.state_qos, config.state_retain, hex.clone())
+            .await
+        {
+            error!("Failed to publish current hex colour: {e}");
+        } else {
+            self.published_hex = Some((Instant::now(), hex));
+        }
+    }
+
+    /// Whether the payload is the state this light last published itself
+    pub fn is_own_publish(&self, payload: &[u8]) -> bool {
+        self.published_state.as_deref() == Some(payload)
+    }
+
+    fn hex_color(&self) -> String {
+        let hsv = if self.state == OnState::On {
+            Hsv::new(self.color.h, self.color.s / 100.0, self.brightness as f32 / 255.0)
+        } else {
+            Hsv::default()
+        };
+
+        let rgb: Rgb<encoding::Srgb, u8> = Rgb::from_color(hsv).into_format();
+        format!("#{:02X}{:02X}{:02X}", rgb.red, rgb.green, rgb.blue)
+    }
+
+    /// How long in seconds one cycle of the mode's animation takes at the current speed
+    fn cycle_time(&self) -> f32 {
+        // the rainbow speed is a measure of how long it should take to go through one cycle, e.g. for the rainbow to
+        // go through the 360 degrees of the colour wheel. the value is between 0 and 100 where 0 = slowest, i.e.
+        // longest time and 100 = fastest, i.e. quickest time. the slope constant provides this mapping
 
         // the maximum speed is the start of the range. since the slope is negative, this will decrease the time as the
         // speed increases
-        let rainbow_time = MAX_RAINBOW_SPEED_S + RAINBOW_SPEED_SLOPE * self.rainbow_speed;
-        let steps_in_time = rainbow_time / step_duration;
+        MAX_RAINBOW_SPEED_S + RAINBOW_SPEED_SLOPE * self.rainbow_speed
+    }
+
+    fn step_hue(&mut self, step_duration: f32) {
+        if let Some(range) = self.mode_params.rainbow_range {
+            // within a range, the hue follows the phase there and back, from the start (or the end when reversed)
+            self.step_phase(step_duration);
+
+            let there_and_back = 1. - (2. * self.animation.phase - 1.).abs();
+            let position = if self.mode_params.rainbow_reverse {
+                1. - there_and_back
+            } else {
+                there_and_back
+            };
+            let span = (range.end - range.start).rem_euclid(360.);
+
+            self.color = Color {
+                h: (range.start + span * position).rem_euclid(360.),
+                ..self.color
+            };
+
+            return;
+        }
+
+        // by knowing how often the steps are taken, calculate how long each step should be to achieve the correct time
+        let steps_in_time = self.cycle_time() / step_duration;
         let step_size = 360.0 / steps_in_time;
+        let step_size = if self.mode_params.rainbow_reverse {
+            -step_size
+        } else {
+            step_size
+        };
 
         self.color = Color {
-            h: (self.color.h + step_size) % 360.0,
+            h: (self.color.h + step_size).rem_euclid(360.0),
             ..self.color
         };
     }
 
-    pub async fn apply(&mut self, config: &Config) -> anyhow::Result<()> {
-        if self.transition {
-            self.apply_transition(config).await
-        } else {
-            self.apply_immediate(config).await
+    fn step_phase(&mut self, step_duration: f32) {
+        self.animation.phase = (self.animation.phase + step_duration / self.cycle_time()) % 1.0;
+    }
+
+    fn step_strobe(&mut self) {
+        let started = *self.strobe_started.get_or_insert_with(Instant::now);
+        let hz = self.mode_params.strobe_hz.unwrap_or(DEFAULT_STROBE_HZ);
+        self.animation.phase = (started.elapsed().as_secs_f32() * hz) % 1.0;
+    }
+
+    /// Steps through the palette over one cycle, holding each colour for a while before moving towards the next,
+    /// wrapping around from the last back to the first
+    fn step_palette(&mut self, step_duration: f32, palette: &[Color]) {
+        if palette.is_empty() {
+            return;
         }
+
+        self.step_phase(step_duration);
+
+        let position = self.animation.phase * palette.len() as f32;
+        let index = (position.floor() as usize).min(palette.len() - 1);
+        let (from, to) = (palette[index], palette[(index + 1) % palette.len()]);
+        let fraction = ((position.fract() - PALETTE_HOLD) / (1. - PALETTE_HOLD)).max(0.);
+
+        // go around the colour wheel the shorter way
+        let hue_change = (to.h - from.h + 540.) % 360. - 180.;
+
+        self.color = Color {
+            h: (from.h + hue_change * fraction).rem_euclid(360.),
+            s: from.s + (to.s - from.s) * fraction,
+        };
     }
 
-    async fn apply_immediate(&self, config: &Config) -> anyhow::Result<()> {
-        let hsv = if self.state == OnState::On {
-            Hsv::new(self.color.h, self.color.s / 100.0, self.brightness as f32 / 255.0)
+    /// Flickers the fire towards a new random brightness and hue every step. Following them through a low-pass filter
+    /// instead of jumping to them keeps the flicker from looking like a strobe
+    fn step_fire(&mut self, step_duration: f32) {
+        let brightness = self.rng.gen_range(FIRE_MIN_BRIGHTNESS..=1.);
+        let hue_offset = self.rng.gen_range(-FIRE_HUE_JITTER..=FIRE_HUE_JITTER);
+        let smoothing = 1. - (-step_duration / FIRE_SMOOTHING_S).exp();
+
+        self.flicker.brightness += (brightness - self.flicker.brightness) * smoothing;
+        self.flicker.hue_offset += (hue_offset - self.flicker.hue_offset) * smoothing;
+
+        self.color = Color {
+            h: (FIRE_HUE + self.flicker.hue_offset).rem_euclid(360.),
+            s: 100.,
+        };
+    }
+
+    /// The factor the brightness is scaled with at the current point of the mode's animation
+    fn envelope(&self, config: &Config) -> f32 {
+        match self.mode {
+            // the pulse rises linearly from 0 to 1 over the rise fraction of the cycle, and falls back to 0 over the
+            // rest of it, so it's continuous over cycle boundaries
+            Mode::Pulse if self.animation.phase < config.pulse_rise => self.animation.phase / config.pulse_rise,
+            Mode::Pulse => (1. - self.animation.phase) / (1. - config.pulse_rise),
+            // breathing eases from full brightness down to 0 and back up over the cycle
+            Mode::Breathing => (1. + (2. * PI * self.animation.phase).cos()) / 2.,
+            // the strobe is fully on for the first half of each flash and black for the rest
+            Mode::Strobe if self.animation.phase < 0.5 => 1.,
+            Mode::Strobe => 0.,
+            Mode::Fire => self.flicker.brightness,
+            Mode::Static | Mode::Rainbow | Mode::Palette => 1.,
+        }
+    }
+
+    /// The factor the brightness is scaled with at the current point of the acknowledgement dip. It eases down and
+    /// back up over the dip's length, leaving the light exactly as it was
+    fn ack_dip(&self) -> f32 {
+        self.ack
+            .map_or(1., |elapsed| 1. - ACK_DEPTH * (PI * elapsed / ACK_LENGTH_S).sin())
+    }
+
+    fn step_ack(&mut self, step_duration: f32) {
+        if let Some(elapsed) = self.ack {
+            let elapsed = elapsed + step_duration;
+            self.ack = (elapsed < ACK_LENGTH_S).then_some(elapsed);
+        }
+    }
+
+    fn step_flash(&mut self, step_duration: f32) {
+        if let Some(flash) = &mut self.flash {
+            flash.elapsed += step_duration;
+
+            if flash.elapsed >= flash.length {
+                self.flash = None;
+                debug!("Flash complete");
+            }
+        }
+    }
+
+    /// Whether the light's output changes on its own and has to be stepped regularly
+    pub fn is_animated(&self) -> bool {
+        (self.state == OnState::On && self.mode.is_animated())
+            || self.transition.is_some()
+            || self.ack.is_some()
+            || self.flash.is_some()
+    }
+
+    pub fn in_transition(&self) -> bool {
+        self.transition.is_some()
+    }
+
+    /// Stops a transition in progress, leaving the brightness where the transition got to
+    pub fn halt_transition(&mut self) {
+        let target_brightness = self.target_brightness();
+
+        if let Some(transition) = self.transition.take() {
+            let (color, value) = transition.color(self.color);
+            self.color = color;
+
+            if self.state == OnState::On {
+                self.brightness = (transition.brightness(target_brightness) * value * 255.).round() as u8;
+            }
+        }
+    }
+
+    /// Takes one step in the active animations
+    pub fn step(&mut self, config: &Config) {
+        let step_duration = config.step_duration;
+
+        if self.state == OnState::On {
+            match self.mode {
+                Mode::Rainbow => self.step_hue(step_duration),
+                Mode::Pulse | Mode::Breathing => self.step_phase(step_duration),
+                Mode::Strobe => self.step_strobe(),
+                Mode::Palette => self.step_palette(step_duration, &config.palette),
+                Mode::Fire => self.step_fire(step_duration),
+                Mode::Static => (),
+            }
+        }
+
+        self.step_transition();
+        self.step_ack(step_duration);
+        self.step_flash(step_duration);
+    }
+
+    pub fn schedule(&mut self, at: NaiveDateTime, command: ControlMessage) {
+        self.scheduled.push(ScheduledCommand { at, command });
+    }
+
+    /// The time of the next scheduled command
+    pub fn next_scheduled(&self) -> Option<NaiveDateTime> {
+        self.scheduled.iter().map(|scheduled| scheduled.at).min()
+    }
+
+    /// Removes and returns the scheduled commands that are due by the given time, in the order they're due
+    pub fn take_due_scheduled(&mut self, now: NaiveDateTime) -> Vec<ControlMessage> {
+        let (mut due, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition(|scheduled| scheduled.at <= now);
+
+        self.scheduled = pending;
+        due.sort_by_key(|scheduled| scheduled.at);
+        due.into_iter().map(|scheduled| scheduled.command).collect()
+    }
+
+    /// Queues a command to be applied once the transition in progress completes
+    pub fn queue_command(&mut self, msg: ControlMessage) {
+        self.queued_commands.push_back(msg);
+    }
+
+    /// Takes the next queued command if there's no transition in progress
+    pub fn take_queued_command(&mut self) -> Option<ControlMessage> {
+        if self.in_transition() {
+            None
         } else {
-            Hsv::default()
+            self.queued_commands.pop_front()
+        }
+    }
+
+    pub async fn apply(&mut self, config: &Config, output: &mut Output) -> anyhow::Result<()> {
+        if let Some(pending) = self.pending_transition.take() {
+            self.start_transition(pending, config);
+        }
+
+        self.apply_immediate(config, output).await
+    }
+
+    async fn apply_immediate(&self, config: &Config, output: &mut Output) -> anyhow::Result<()> {
+        let color = self.output_color();
+        let hsv = match self.flash {
+            // the flash is meant to be noticed, so it's at full brightness even if the light is dim or off
+            Some(flash) if flash.is_lit() => {
+                let color = flash.color.unwrap_or(color);
+                Hsv::new(color.h, self.output_saturation(color, config) / 100.0, 1.0)
+            }
+            Some(_) => Hsv::default(),
+            None => Hsv::new(
+                color.h,
+                self.output_saturation(color, config) / 100.0,
+                self.output_brightness() * self.envelope(config) * self.ack_dip(),
+            ),
         };
 
-        write_hsv_to_blaster(hsv, config).await
+        write_hsv_to_blaster(hsv, config, output).await
     }
 
-    async fn apply_transition(&mut self, config: &Config) -> anyhow::Result<()> {
-        self.transition = false;
+    /// The RGB values, between 0 and 255, the light shows once any transition completes, at the peak of an animated
+    /// mode's brightness
+    fn settled_rgb(&self, config: &Config) -> ReportedRgb {
+        let hsv = Hsv::new(
+            self.color.h,
+            self.output_saturation(self.color, config) / 100.0,
+            self.target_brightness(),
+        );
 
-        let initial_brightness = self.brightness as f32 / 255.;
-        let steps_in_time = TRANSITION_LENGTH_S / config.step_duration;
-        let step_size = initial_brightness / steps_in_time;
-        let sleep_duration = Duration::from_secs_f32(config.step_duration);
+        let rgb: Rgb<encoding::Srgb, u8> = hsv_to_output_rgb(hsv, config).into_format();
+        ReportedRgb {
+            r: rgb.red,
+            g: rgb.green,
+            b: rgb.blue,
+        }
+    }
+
+    /// The saturation the light shows, between 0 and 100. The configured minimum saturation keeps colours from drifting
+    /// into a white that looks like the colour is broken
+    fn output_saturation(&self, color: Color, config: &Config) -> f32 {
+        let saturation = self.swept_color(color).s;
+
+        // the whites of colour temperatures are meant to be unsaturated
+        match config.min_saturation {
+            Some(min_saturation) if self.color_mode != ColorMode::ColorTemp => saturation.max(min_saturation),
+            _ => saturation,
+        }
+    }
+
+    /// The colour with the rainbow's saturation sweep, if it's sweeping
+    fn swept_color(&self, color: Color) -> Color {
+        match (self.mode, self.mode_params.rainbow_saturation) {
+            (Mode::Rainbow, Some(sweep)) => Color {
+                s: sweep.saturation(color.h),
+                ..color
+            },
+            _ => color,
+        }
+    }
 
-        let (mut current_brightness, target_brightness, step_size) = if self.state == OnState::On {
-            // current state on means we're transitioning from off to on, and have to use a positive step
-            (0., initial_brightness, step_size)
+    /// The brightness the light's state calls for, between 0 and 1
+    fn target_brightness(&self) -> f32 {
+        if self.state == OnState::On {
+            self.night_cap.map_or(self.brightness, |cap| self.brightness.min(cap)) as f32 / 255.
         } else {
-            // current state off means we're transitioning from on to off, and have to use a negative step
-            (initial_brightness, 0., -step_size)
+            0.
+        }
+    }
+
+    /// The brightness the light is currently showing, between 0 and 1
+    fn output_brightness(&self) -> f32 {
+        let target_brightness = self.target_brightness();
+        self.transition.map_or(target_brightness, |transition| {
+            transition.brightness(target_brightness) * transition.color(self.color).1
+        })
+    }
+
+    /// The colour the light is currently showing
+    fn output_color(&self) -> Color {
+        self.transition
+            .map_or(self.color, |transition| transition.color(self.color).0)
+    }
+
+    /// Starts a pending transition from what the light was showing before it was edited. A transition still in
+    /// progress is superseded by this one, which continues from wherever it got to
+    fn start_transition(&mut self, pending: PendingTransition, config: &Config) {
+        let PendingTransition {
+            length,
+            from_brightness,
+            fade_brightness,
+            from_color,
+            easing,
+            interpolation,
+        } = pending;
+        let target_brightness = self.target_brightness();
+        let current_brightness = if fade_brightness {
+            from_brightness
+        } else {
+            target_brightness
+        };
+
+        let change = (target_brightness - current_brightness).abs();
+        let length = if config.scale_transitions {
+            // the transition takes as long as the greater of the changes in the brightness and the colour calls for
+            let color_change = from_color.map_or(0., |from| color_change(from, self.color));
+            let length = (length * change.max(color_change)).max(config.min_transition_duration);
+            config.max_transition_duration.map_or(length, |max| length.min(max))
+        } else {
+            length
         };
 
-        let brightness_range_end = target_brightness.max(initial_brightness);
+        let steps_in_time = length / config.step_duration;
+
+        // a zero-length transition would be over in no steps at all, so it's never started and the state is applied
+        // immediately
+        if length <= 0. || (change == 0. && from_color.is_none()) {
+            self.transition = None;
+            return;
+        }
 
         debug!(
-            "Transitioning to {:?}. {current_brightness} -> {target_brightness} in {steps_in_time} steps of \
-             {step_size} over {TRANSITION_LENGTH_S}s",
+            "Transitioning to {:?}. {current_brightness} -> {target_brightness} in {steps_in_time} steps with \
+             {easing:?} easing over {length}s",
             self.state
         );
 
-        // apply the current first brightness since the loop steps the brightness before applying
-        let hsv = Hsv::new(self.color.h, self.color.s / 100.0, current_brightness);
-        write_hsv_to_blaster(hsv, config).await?;
-
-        loop {
-            current_brightness += step_size;
-            debug!("{current_brightness}");
-
-            let hsv = Hsv::new(
-                self.color.h,
-                self.color.s / 100.0,
-                // clamp the brightness value between 0 and the larger of the target brightness (going up) or the
-                // initial brightness (going down). the clamp is set here instead of to the brightness value directly
-                // to ensure the last iteration step takes it outside the transition brightness range and the loop
-                // terminates
-                current_brightness.clamp(0., brightness_range_end),
-            );
+        self.transition = Some(Transition {
+            from: current_brightness,
+            from_color,
+            progress: 0.,
+            progress_step: 1. / steps_in_time,
+            easing,
+            interpolation,
+        });
+    }
 
-            write_hsv_to_blaster(hsv, config).await?;
+    fn step_transition(&mut self) {
+        let target_brightness = self.target_brightness();
 
-            if !(0.0..brightness_range_end).contains(&current_brightness) {
-                break;
-            }
+        if let Some(transition) = &mut self.transition {
+            // the transition moves towards the brightness the state calls for at the moment, so if it changes
+            // mid-transition, the transition follows it
+            transition.progress += transition.progress_step;
 
-            tokio::time::sleep(sleep_duration).await;
+            if transition.progress >= 1. {
+                // once the transition is done, the light shows exactly what the state calls for, e.g. true black
+                // after fading out
+                self.transition = None;
+                debug!("Transition complete");
+            } else {
+                debug!("{}", transition.brightness(target_brightness));
+            }
         }
+    }
+}
 
-        debug!("Transition complete");
-        Ok(())
+/// Shows the given colour at full brightness as a visual alarm. The light keeps showing it after the process exits.
+/// This is a best-effort attempt which only logs a failure, since it's used when things have already gone wrong.
+pub async fn show_panic_color(color: Color, config: &Config) {
+    let hsv = Hsv::new(color.h, color.s / 100.0, 1.0);
+
+    let result = match Output::new(config).await {
+        Ok(mut output) => write_hsv_to_blaster(hsv, config, &mut output).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        error!("Failed to show the panic colour: {e}");
     }
 }
 
-async fn write_hsv_to_blaster(hsv: Hsv<encoding::Srgb, f32>, config: &Config) -> anyhow::Result<()> {
-    let rgb = Rgb::from_color(hsv);
-    let msg = format!(
-        "{pin_r}={r} {pin_g}={g} {pin_b}={b}\n",
-        pin_r = config.pin_r,
-        pin_g = config.pin_g,
-        pin_b = config.pin_b,
-        r = rgb.red,
-        g = rgb.green,
-        b = rgb.blue
-    );
+/// How much a colour changes, between 0 for no change and 1 for a change to the opposite hue or across the whole range
+/// of saturation. The hue's change counts for less the less saturated the colours are, since the less the hue shows
+fn color_change(from: Color, to: Color) -> f32 {
+    let hue_change = ((to.h - from.h + 180.).rem_euclid(360.) - 180.).abs() / 180.;
+    let saturation = from.s.max(to.s) / 100.;
+    let saturation_change = (to.s - from.s).abs() / 100.;
+
+    (hue_change * saturation + saturation_change).min(1.)
+}
+
+/// Approximates the colour of a black body at a colour temperature, given in mireds
+fn color_temp_to_color(mireds: u16) -> Color {
+    // the curves fitted to the black body's RGB by Tanner Helland, in hundreds of kelvin
+    let temperature = 1_000_000. / mireds as f32 / 100.;
+
+    let red = if temperature <= 66. {
+        255.
+    } else {
+        329.69873 * (temperature - 60.).powf(-0.13320476)
+    };
+    let green = if temperature <= 66. {
+        99.4708 * temperature.ln() - 161.11957
+    } else {
+        288.12216 * (temperature - 60.).powf(-0.07551485)
+    };
+    let blue = if temperature >= 66. {
+        255.
+    } else if temperature <= 19. {
+        0.
+    } else {
+        138.51773 * (temperature - 10.).ln() - 305.0448
+    };
+
+    let channel = |c: f32| c.clamp(0., 255.) / 255.;
+    let hsv: Hsv<encoding::Srgb, f32> = Hsv::from_color(Rgb::new(channel(red), channel(green), channel(blue)));
+
+    Color {
+        h: hsv.hue.into_positive_degrees(),
+        s: hsv.saturation * 100.,
+    }
+}
+
+/// Maps a brightness between 0 and 1 through a lookup table of output brightnesses at evenly spaced inputs,
+/// interpolating linearly between the points
+fn apply_brightness_curve(brightness: f32, curve: &[f32]) -> f32 {
+    let position = brightness.clamp(0., 1.) * (curve.len() - 1) as f32;
+    let index = (position.floor() as usize).min(curve.len() - 2);
+    let fraction = position - index as f32;
+
+    curve[index] + (curve[index + 1] - curve[index]) * fraction
+}
+
+/// Scales all channels down by the same factor if their sum exceeds the power budget. Scaling them equally keeps the
+/// hue and saturation and only lowers the brightness.
+fn limit_power(rgb: Rgb, power_budget: Option<f32>) -> Rgb {
+    let total = rgb.red + rgb.green + rgb.blue;
+
+    match power_budget {
+        Some(budget) if total > budget => rgb * (budget / total),
+        _ => rgb,
+    }
+}
+
+/// Converts a colour to the RGB values written to the blaster, applying all the output adjustments. The brightness
+/// goes through the knee shaping the slider's feel, the curve calibrating the light and the minimum brightness floor,
+/// then each channel goes through the gamma correction, and finally the power limit
+fn hsv_to_output_rgb(hsv: Hsv<encoding::Srgb, f32>, config: &Config) -> Rgb {
+    let mut hsv = hsv;
+
+    if let Some(knee) = config.brightness_knee {
+        hsv.value = hsv.value.clamp(0., 1.).powf(knee);
+    }
+
+    if let Some(curve) = &config.brightness_curve {
+        hsv.value = apply_brightness_curve(hsv.value, curve);
+    }
+
+    // anything that's on is lifted above the drivers' dead zone by remapping the range above the floor, but off is
+    // still fully off
+    if let Some(floor) = config.min_brightness {
+        if hsv.value > 0. {
+            hsv.value = floor + (1. - floor) * hsv.value.min(1.);
+        }
+    }
 
-    debug!(
-        "Writing to blaster: {hsv:?} -> {rgb:?} -> \"{}\"",
-        &msg[..msg.len() - 1],
+    // the white balance is calibrated on the linear colour, so the gamma applies on top of it like it does without
+    // the calibration
+    let rgb: Rgb = Rgb::from_color(hsv);
+    let rgb: Rgb = Rgb::new(
+        rgb.red * config.scale_r,
+        rgb.green * config.scale_g,
+        rgb.blue * config.scale_b,
     );
 
-    let mut blaster = OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(false)
-        .open(&config.blaster)
-        .await?;
+    let gamma = |component: f32| component.clamp(0., 1.).powf(config.gamma);
+    let rgb = Rgb::new(gamma(rgb.red), gamma(rgb.green), gamma(rgb.blue));
+
+    limit_power(rgb, config.power_budget)
+}
+
+async fn write_hsv_to_blaster(
+    hsv: Hsv<encoding::Srgb, f32>,
+    config: &Config,
+    output: &mut Output,
+) -> anyhow::Result<()> {
+    let rgb = hsv_to_output_rgb(hsv, config);
+    debug!("Writing {hsv:?} as {rgb:?}");
+
+    write_rgb_to_blaster(rgb, config, output).await
+}
+
+/// Writes the last frame written to the light again, in case something else has reset the pins since
+pub async fn refresh_output(config: &Config, output: &mut Output) -> anyhow::Result<()> {
+    if let Some(rgb) = output.last_frame() {
+        debug!("Refreshing output");
+        write_rgb_to_blaster(rgb, config, output).await?;
+    }
 
-    blaster.write_all(msg.as_bytes()).await?;
     Ok(())
 }
+
+/// Turns all the light's channels off, e.g. before moving the light to other pins
+pub async fn blank_output(config: &Config, output: &mut Output) -> anyhow::Result<()> {
+    write_rgb_to_blaster(Rgb::new(0., 0., 0.), config, output).await
+}
+
+/// Moves the white shared by all the channels to a white channel. The less saturated the colour, the more of it is
+/// shown by the white channel instead of mixing it from the RGB channels
+fn split_white(rgb: Rgb) -> (Rgb, f32) {
+    let white = rgb.red.min(rgb.green).min(rgb.blue);
+    (Rgb::new(rgb.red - white, rgb.green - white, rgb.blue - white), white)
+}
+
+async fn write_rgb_to_blaster(rgb: Rgb, config: &Config, output: &mut Output) -> anyhow::Result<()> {
+    let (channels, white) = match config.pin_w {
+        Some(_) => {
+            let (channels, white) = split_white(rgb);
+            (channels, Some(white))
+        }
+        None => (rgb, None),
+    };
+
+    // common-anode LEDs are lit while their pin is low, so the duty cycles are the other way around
+    let invert = |channel: Channel, component: f32| {
+        if config.is_inverted(channel) {
+            1. - component.clamp(0., 1.)
+        } else {
+            component
+        }
+    };
+    let channels = Rgb::new(
+        invert(Channel::Red, channels.red),
+        invert(Channel::Green, channels.green),
+        invert(Channel::Blue, channels.blue),
+    );
+    let white = white.map(|white| invert(Channel::White, white));
+
+    output.write_light(channels, white, config).await?;
+    output.send_frame(rgb).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::Recording;
+
+    const RED: Color = Color { h: 0., s: 100. };
+    const GREEN: Color = Color { h: 120., s: 100. };
+    const PINK: Color = Color { h: 330., s: 40. };
+
+    fn command(json: &str) -> ControlMessage {
+        serde_json::from_str(json).expect("invalid command")
+    }
+
+    /// A lit state in the given mode and colour at full brightness
+    fn lit(mode: Mode, color: Color) -> State {
+        State {
+            state: OnState::On,
+            mode,
+            color,
+            ..Default::default()
+        }
+    }
+
+    fn turned_on(brightness: u8, config: &Config) -> State {
+        let mut state = State::default();
+        state.edit(
+            ControlMessage {
+                state: Some(OnState::On),
+                brightness: Some(brightness),
+                ..Default::default()
+            },
+            config,
+        );
+        state
+    }
+
+    #[test]
+    fn night_cap_keeps_commanded_brightness() {
+        let config = Config::for_tests(&[]);
+        let mut state = turned_on(200, &config);
+
+        state.set_night_cap(Some(25), 0., &config);
+        assert_eq!(state.brightness, 200);
+        assert_eq!(state.target_brightness(), 25. / 255.);
+
+        state.edit(
+            ControlMessage {
+                brightness: Some(100),
+                ..Default::default()
+            },
+            &config,
+        );
+        assert_eq!(state.brightness, 100);
+        assert_eq!(state.target_brightness(), 25. / 255.);
+
+        state.set_night_cap(None, 0., &config);
+        assert_eq!(state.target_brightness(), 100. / 255.);
+    }
+
+    #[test]
+    fn night_cap_transitions_only_a_change_in_what_is_shown() {
+        let config = Config::for_tests(&[]);
+
+        let mut state = turned_on(200, &config);
+        state.pending_transition = None;
+        state.set_night_cap(Some(25), 1., &config);
+        assert!(state
+            .pending_transition
+            .is_some_and(|pending| pending.from_brightness == 200. / 255.));
+
+        let mut state = turned_on(10, &config);
+        state.pending_transition = None;
+        state.set_night_cap(Some(25), 1., &config);
+        assert!(state.pending_transition.is_none());
+    }
+
+    #[test]
+    fn transition_length_precedence() {
+        let config = Config::for_tests(&[("STEP_DURATION", "0.1")]);
+        let state = turned_on(200, &config);
+        let length = |transition, transition_steps| {
+            let msg = ControlMessage {
+                brightness: Some(100),
+                transition,
+                transition_steps,
+                ..Default::default()
+            };
+            state
+                .edited(&msg, &config, Instant::now())
+                .state
+                .pending_transition
+                .map(|pending| pending.length)
+        };
+
+        assert_eq!(length(None, None), None);
+        assert_eq!(length(Some(2.), None), Some(2.));
+        assert_eq!(length(None, Some(10)), Some(10. * 0.1));
+        assert_eq!(length(Some(2.), Some(10)), Some(2.));
+    }
+
+    #[test]
+    fn edited_table() {
+        let off = State::default;
+        let cases = [
+            // (rainbow colour policy, state, command, mode, colour, brightness, on state, ignored colour)
+            (
+                "ignore",
+                off(),
+                r#"{"state": "ON"}"#,
+                Mode::Static,
+                off().color,
+                255,
+                OnState::On,
+                None,
+            ),
+            (
+                "ignore",
+                off(),
+                r#"{"brightness": 50}"#,
+                Mode::Static,
+                off().color,
+                50,
+                OnState::On,
+                None,
+            ),
+            (
+                "ignore",
+                lit(Mode::Static, RED),
+                r#"{"state": "OFF"}"#,
+                Mode::Static,
+                RED,
+                255,
+                OnState::Off,
+                None,
+            ),
+            (
+                "ignore",
+                lit(Mode::Static, RED),
+                r#"{"brightness": 20}"#,
+                Mode::Static,
+                RED,
+                20,
+                OnState::On,
+                None,
+            ),
+            (
+                "ignore",
+                lit(Mode::Static, RED),
+                r#"{"color": {"h": 330, "s": 40}}"#,
+                Mode::Static,
+                PINK,
+                255,
+                OnState::On,
+                None,
+            ),
+            (
+                "ignore",
+                lit(Mode::Static, RED),
+                r#"{"mode": "Rainbow"}"#,
+                Mode::Rainbow,
+                RED,
+                255,
+                OnState::On,
+                None,
+            ),
+            (
+                "ignore",
+                lit(Mode::Rainbow, GREEN),
+                r#"{"color": {"h": 330, "s": 40}}"#,
+                Mode::Rainbow,
+                GREEN,
+                255,
+                OnState::On,
+                Some(PINK),
+            ),
+            (
+                "apply",
+                lit(Mode::Rainbow, GREEN),
+                r#"{"color": {"h": 330, "s": 40}}"#,
+                Mode::Rainbow,
+                PINK,
+                255,
+                OnState::On,
+                None,
+            ),
+            (
+                "pause",
+                lit(Mode::Rainbow, GREEN),
+                r#"{"color": {"h": 330, "s": 40}}"#,
+                Mode::Static,
+                PINK,
+                255,
+                OnState::On,
+                None,
+            ),
+            (
+                "ignore",
+                lit(Mode::Rainbow, GREEN),
+                r#"{"mode": "Static", "color": {"h": 330, "s": 40}, "brightness": 10}"#,
+                Mode::Static,
+                PINK,
+                10,
+                OnState::On,
+                None,
+            ),
+        ];
+
+        for (policy, state, msg, mode, color, brightness, on_state, ignored_color) in cases {
+            let config = Config::for_tests(&[("RAINBOW_COLOR", policy)]);
+            let edited = state.edited(&command(msg), &config, Instant::now());
+
+            assert_eq!(edited.state.mode, mode, "{policy}: {msg}");
+            assert_eq!(edited.state.color, color, "{policy}: {msg}");
+            assert_eq!(edited.state.brightness, brightness, "{policy}: {msg}");
+            assert_eq!(edited.state.state, on_state, "{policy}: {msg}");
+            assert_eq!(edited.ignored_color, ignored_color, "{policy}: {msg}");
+        }
+    }
+
+    #[test]
+    fn rainbow_color_policies() {
+        let with_color = r#"{"color": {"h": 330, "s": 40}}"#;
+        let without_color = r#"{"brightness": 100}"#;
+        let keeping_rainbow = r#"{"mode": "Rainbow", "color": {"h": 330, "s": 40}}"#;
+
+        for policy in ["ignore", "apply", "pause"] {
+            let config = Config::for_tests(&[("RAINBOW_COLOR", policy)]);
+            let edited = |state: State, msg| {
+                let edited = state.edited(&command(msg), &config, Instant::now());
+                (edited.state.mode, edited.state.color, edited.ignored_color)
+            };
+
+            // the static mode takes any colour whatever the policy
+            assert_eq!(
+                edited(lit(Mode::Static, RED), with_color),
+                (Mode::Static, PINK, None),
+                "{policy}"
+            );
+            assert_eq!(
+                edited(lit(Mode::Static, RED), without_color),
+                (Mode::Static, RED, None),
+                "{policy}"
+            );
+            assert_eq!(
+                edited(lit(Mode::Rainbow, GREEN), without_color),
+                (Mode::Rainbow, GREEN, None),
+                "{policy}"
+            );
+
+            let (rainbow, kept_rainbow) = match policy {
+                "ignore" => ((Mode::Rainbow, GREEN, Some(PINK)), (Mode::Rainbow, GREEN, Some(PINK))),
+                "apply" => ((Mode::Rainbow, PINK, None), (Mode::Rainbow, PINK, None)),
+                _ => ((Mode::Static, PINK, None), (Mode::Rainbow, PINK, None)),
+            };
+            assert_eq!(edited(lit(Mode::Rainbow, GREEN), with_color), rainbow, "{policy}");
+            assert_eq!(
+                edited(lit(Mode::Rainbow, GREEN), keeping_rainbow),
+                kept_rainbow,
+                "{policy}"
+            );
+        }
+    }
+
+    #[test]
+    fn scaled_color_transition_length() {
+        let config = Config::for_tests(&[("SCALE_TRANSITIONS", "true"), ("STEP_DURATION", "0.1")]);
+        let length = |from: Color, to: Color| {
+            let mut state = lit(Mode::Static, to);
+            state.start_transition(
+                PendingTransition {
+                    length: 10.,
+                    from_brightness: state.target_brightness(),
+                    fade_brightness: true,
+                    from_color: Some(from),
+                    easing: Easing::Linear,
+                    interpolation: ColorInterpolation::Hsv,
+                },
+                &config,
+            );
+            state
+                .transition
+                .map(|transition| config.step_duration / transition.progress_step)
+        };
+
+        let slight = length(RED, Color { h: 9., s: 100. }).expect("no transition");
+        assert!((slight - 0.5).abs() < 1e-3, "{slight}");
+
+        let desaturating = length(RED, Color { h: 0., s: 75. }).expect("no transition");
+        assert!((desaturating - 2.5).abs() < 1e-3, "{desaturating}");
+
+        let opposite = length(GREEN, Color { h: 300., s: 100. }).expect("no transition");
+        assert!((opposite - 10.).abs() < 1e-3, "{opposite}");
+    }
+
+    #[test]
+    fn transition_brightness_and_color_independently() {
+        let cases = [
+            // (config, command, brightness and colour shown once the transition starts)
+            ((true, true), (None, None), (1., RED)),
+            ((true, true), (Some(false), None), (100. / 255., RED)),
+            ((true, true), (None, Some(false)), (1., GREEN)),
+            ((true, true), (Some(false), Some(false)), (100. / 255., GREEN)),
+            ((false, true), (None, None), (100. / 255., RED)),
+            ((true, false), (None, None), (1., GREEN)),
+            ((false, false), (None, None), (100. / 255., GREEN)),
+            ((false, false), (Some(true), Some(true)), (1., RED)),
+        ];
+
+        for ((brightness, color), (transition_brightness, transition_color), (shown_brightness, shown_color)) in cases {
+            let config = Config::for_tests(&[
+                ("TRANSITION_BRIGHTNESS", if brightness { "true" } else { "false" }),
+                ("TRANSITION_COLOR", if color { "true" } else { "false" }),
+            ]);
+            let msg = ControlMessage {
+                color: Some(GREEN),
+                brightness: Some(100),
+                transition: Some(2.),
+                transition_brightness,
+                transition_color,
+                ..Default::default()
+            };
+
+            let mut state = lit(Mode::Static, RED);
+            state.edit(msg, &config);
+            if let Some(pending) = state.pending_transition.take() {
+                state.start_transition(pending, &config);
+            }
+
+            let case = (brightness, color, transition_brightness, transition_color);
+            assert_eq!(state.output_brightness(), shown_brightness, "{case:?}");
+            assert_eq!(state.output_color(), shown_color, "{case:?}");
+        }
+    }
+
+    #[test]
+    fn color_interpolation_midpoint() {
+        let midpoint = |interpolation| {
+            let transition = Transition {
+                from: 1.,
+                from_color: Some(RED),
+                progress: 0.5,
+                progress_step: 0.1,
+                easing: Easing::Linear,
+                interpolation,
+            };
+            let (color, value) = transition.color(GREEN);
+            Srgb::from_color(Hsv::new(color.h, color.s / 100., value)).into_linear()
+        };
+        // how far the brightest channel is from the dimmest, which is 1 for the vivid red and green themselves
+        let chroma =
+            |rgb: palette::LinSrgb| rgb.red.max(rgb.green).max(rgb.blue) - rgb.red.min(rgb.green).min(rgb.blue);
+
+        // the way around the wheel passes through a vivid yellow
+        let hsv = midpoint(ColorInterpolation::Hsv);
+        assert!((chroma(hsv) - 1.).abs() < 1e-3, "{hsv:?}");
+
+        // the straight line passes halfway between red and green, as dim and desaturated as half of each
+        let rgb = midpoint(ColorInterpolation::LinearRgb);
+        assert!(
+            (rgb.red - 0.5).abs() < 1e-3 && (rgb.green - 0.5).abs() < 1e-3 && rgb.blue < 1e-3,
+            "{rgb:?}"
+        );
+        assert!(chroma(rgb) < 0.51, "{rgb:?}");
+    }
+
+    #[test]
+    fn color_interpolation_endpoints() {
+        for interpolation in [ColorInterpolation::Hsv, ColorInterpolation::LinearRgb] {
+            for progress in [0., 1.] {
+                let transition = Transition {
+                    from: 1.,
+                    from_color: Some(RED),
+                    progress,
+                    progress_step: 0.1,
+                    easing: Easing::Linear,
+                    interpolation,
+                };
+                let (color, value) = transition.color(PINK);
+                let expected = if progress == 0. { RED } else { PINK };
+
+                let case = (interpolation, progress);
+                assert!((color.h - expected.h).abs() < 1e-2, "{case:?}: {color:?}");
+                assert!((color.s - expected.s).abs() < 1e-2, "{case:?}: {color:?}");
+                assert!((value - 1.).abs() < 1e-4, "{case:?}: {value}");
+            }
+        }
+    }
+
+    #[test]
+    fn power_limit_on_full_white() {
+        let white = Rgb::new(1., 1., 1.);
+
+        let limited = limit_power(white, Some(1.5));
+        assert!(
+            (limited.red + limited.green + limited.blue - 1.5).abs() < 1e-6,
+            "{limited:?}"
+        );
+        assert_eq!(limited.red, limited.green);
+        assert_eq!(limited.green, limited.blue);
+
+        // within the budget, or without one, the channels are left alone
+        assert_eq!(limit_power(white, Some(3.)), white);
+        assert_eq!(limit_power(white, None), white);
+    }
+
+    #[test]
+    fn brightness_curve_interpolation() {
+        let curve = [0., 0.1, 0.5, 1.];
+
+        // the points themselves
+        assert_eq!(apply_brightness_curve(0., &curve), 0.);
+        assert_eq!(apply_brightness_curve(1. / 3., &curve), 0.1);
+        assert_eq!(apply_brightness_curve(1., &curve), 1.);
+
+        // halfway between the middle points, and a quarter of the way into the last segment
+        assert!((apply_brightness_curve(0.5, &curve) - 0.3).abs() < 1e-6);
+        assert!((apply_brightness_curve(0.75, &curve) - 0.625).abs() < 1e-6);
+
+        // out of range brightnesses are clamped to the ends
+        assert_eq!(apply_brightness_curve(-1., &curve), 0.);
+        assert_eq!(apply_brightness_curve(2., &curve), 1.);
+    }
+
+    #[test]
+    fn legacy_state_migrates() {
+        // a state stored before the schema was versioned, missing fields added since and with one removed since
+        let blob = r#"{"color": {"h": 120, "s": 100}, "brightness": 80, "state": "ON", "removed_setting": true}"#;
+        let mut state: State = serde_json::from_str(blob).expect("legacy state rejected");
+        assert_eq!(state.version, 0);
+
+        state.migrate();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.color, GREEN);
+        assert_eq!(state.brightness, 80);
+        assert_eq!(state.state, OnState::On);
+        assert_eq!(state.mode, State::default().mode);
+        assert!(state.scheduled.is_empty());
+    }
+
+    #[test]
+    fn priority_hold() {
+        let config = Config::for_tests(&[("PRIORITY_HOLD", "60")]);
+        let now = Instant::now();
+        let held = lit(Mode::Static, RED)
+            .edited(&command(r#"{"brightness": 10, "priority": 5}"#), &config, now)
+            .state;
+
+        // lower priorities are held off, the same or higher ones aren't
+        assert!(held.is_held_at(0, now));
+        assert!(held.is_held_at(4, now + Duration::from_secs(59)));
+        assert!(!held.is_held_at(5, now));
+        assert!(!held.is_held_at(9, now));
+
+        // the hold expires
+        assert!(!held.is_held_at(0, now + Duration::from_secs(60)));
+
+        // commands without a priority don't hold anything off
+        let unheld = lit(Mode::Static, RED)
+            .edited(&command(r#"{"brightness": 10}"#), &config, now)
+            .state;
+        assert!(!unheld.is_held_at(0, now));
+    }
+
+    #[test]
+    fn brightness_implies_on() {
+        for (implies_on, on_state) in [("true", OnState::On), ("false", OnState::Off)] {
+            let config = Config::for_tests(&[("BRIGHTNESS_IMPLIES_ON", implies_on)]);
+            let edited = |msg| State::default().edited(&command(msg), &config, Instant::now()).state;
+
+            let dimmed = edited(r#"{"brightness": 40}"#);
+            assert_eq!(dimmed.state, on_state, "{implies_on}");
+            assert_eq!(dimmed.brightness, 40, "{implies_on}");
+
+            // an explicit on state wins either way
+            assert_eq!(
+                edited(r#"{"brightness": 40, "state": "OFF"}"#).state,
+                OnState::Off,
+                "{implies_on}"
+            );
+            assert_eq!(
+                edited(r#"{"brightness": 40, "state": "ON"}"#).state,
+                OnState::On,
+                "{implies_on}"
+            );
+            // and without a brightness the light stays off
+            assert_eq!(
+                edited(r#"{"color": {"h": 0, "s": 100}}"#).state,
+                OnState::Off,
+                "{implies_on}"
+            );
+        }
+    }
+
+    #[test]
+    fn manual_hold_window() {
+        let config = Config::for_tests(&[("MANUAL_HOLD", "60")]);
+        let mut state = State::default();
+        assert!(!state.is_manually_held(&config));
+
+        state.mark_manual_change();
+        let changed = state.manual_change.expect("no manual change");
+        assert!(state.is_manually_held_at(&config, changed));
+        assert!(state.is_manually_held_at(&config, changed + Duration::from_secs(59)));
+        assert!(!state.is_manually_held_at(&config, changed + Duration::from_secs(60)));
+
+        // without a hold configured, a manual change holds nothing off
+        assert!(!state.is_manually_held_at(&Config::for_tests(&[]), changed));
+    }
+
+    #[test]
+    fn white_split() {
+        let cases = [
+            // (colour, colour channels, white channel)
+            (Rgb::new(1., 1., 1.), Rgb::new(0., 0., 0.), 1.),
+            (Rgb::new(0.5, 0.5, 0.5), Rgb::new(0., 0., 0.), 0.5),
+            (Rgb::new(1., 0., 0.), Rgb::new(1., 0., 0.), 0.),
+            (Rgb::new(1., 0.5, 0.25), Rgb::new(0.75, 0.25, 0.), 0.25),
+            (Rgb::new(0., 0., 0.), Rgb::new(0., 0., 0.), 0.),
+        ];
+
+        for (rgb, channels, white) in cases {
+            assert_eq!(split_white(rgb), (channels, white), "{rgb:?}");
+        }
+    }
+
+    #[test]
+    fn gamma_correction() {
+        let output = |gamma, value| hsv_to_output_rgb(Hsv::new(0., 0., value), &Config::for_tests(&[("GAMMA", gamma)]));
+
+        // without the correction the mid-range is output as is, with it the mid-range is darker
+        assert_eq!(output("1", 0.5), Rgb::new(0.5, 0.5, 0.5));
+        let corrected = output("2.2", 0.5);
+        assert!((corrected.red - 0.5f32.powf(2.2)).abs() < 1e-6, "{corrected:?}");
+        assert_eq!(corrected.red, corrected.green);
+        assert_eq!(corrected.green, corrected.blue);
+
+        // the ends stay where they are
+        assert_eq!(output("2.2", 0.), Rgb::new(0., 0., 0.));
+        assert_eq!(output("2.2", 1.), Rgb::new(1., 1., 1.));
+    }
+
+    #[test]
+    fn rainbow_mid_sweep_commands() {
+        for policy in ["ignore", "apply", "pause"] {
+            let config = Config::for_tests(&[("RAINBOW_COLOR", policy)]);
+            let mut state = lit(Mode::Rainbow, GREEN);
+            for _ in 0..10 {
+                state.step(&config);
+            }
+            let swept = state.color;
+            assert_ne!(swept, GREEN, "{policy}");
+
+            // a command without a colour keeps the sweep where it is
+            state.edit(command(r#"{"brightness": 100}"#), &config);
+            assert_eq!(
+                (state.mode, state.color, state.brightness),
+                (Mode::Rainbow, swept, 100),
+                "{policy}"
+            );
+
+            state.edit(command(r#"{"color": {"h": 330, "s": 40}}"#), &config);
+            let expected = match policy {
+                "ignore" => (Mode::Rainbow, swept),
+                "apply" => (Mode::Rainbow, PINK),
+                _ => (Mode::Static, PINK),
+            };
+            assert_eq!((state.mode, state.color), expected, "{policy}");
+
+            // the sweep carries on from wherever the colour left it, unless it's paused
+            state.step(&config);
+            match policy {
+                "pause" => assert_eq!(state.color, PINK),
+                _ => assert_ne!(state.color, expected.1, "{policy}"),
+            }
+        }
+    }
+
+    #[test]
+    fn easing_endpoints() {
+        for easing in ["linear", "ease_in", "ease_out", "ease_in_out"] {
+            let parsed: Easing = serde_json::from_value(easing.into()).expect("invalid easing");
+            assert_eq!(parsed.apply(0.), 0., "{easing}");
+            assert_eq!(parsed.apply(1.), 1., "{easing}");
+
+            let config = Config::for_tests(&[("TRANSITION_EASING", easing)]);
+            let mut state = State::default();
+
+            // the transition starts from exactly off, ends at exactly the target and then back at exactly off
+            for (msg, from, to) in [
+                (
+                    r#"{"state": "ON", "brightness": 200, "transition": 1}"#,
+                    0.,
+                    200. / 255.,
+                ),
+                (r#"{"state": "OFF", "transition": 1}"#, 200. / 255., 0.),
+            ] {
+                state.edit(command(msg), &config);
+                let pending = state.pending_transition.take().expect("no transition");
+                state.start_transition(pending, &config);
+                assert_eq!(state.output_brightness(), from, "{easing}: {msg}");
+
+                while state.in_transition() {
+                    state.step(&config);
+                }
+                assert_eq!(state.output_brightness(), to, "{easing}: {msg}");
+            }
+        }
+    }
+
+    #[test]
+    fn white_balance() {
+        let white = Hsv::new(0., 0., 1.);
+
+        let config = Config::for_tests(&[("SCALE_R", "0.5"), ("SCALE_B", "0.8")]);
+        assert_eq!(hsv_to_output_rgb(white, &config), Rgb::new(0.5, 1., 0.8));
+
+        // the calibration applies to the linear colour, before the gamma
+        let config = Config::for_tests(&[("SCALE_R", "0.5"), ("SCALE_B", "0.8"), ("GAMMA", "2.2")]);
+        let rgb = hsv_to_output_rgb(white, &config);
+        assert!((rgb.red - 0.5f32.powf(2.2)).abs() < 1e-6, "{rgb:?}");
+        assert_eq!(rgb.green, 1.);
+        assert!((rgb.blue - 0.8f32.powf(2.2)).abs() < 1e-6, "{rgb:?}");
+
+        // unscaled channels are left alone
+        assert_eq!(hsv_to_output_rgb(white, &Config::for_tests(&[])), Rgb::new(1., 1., 1.));
+    }
+
+    #[tokio::test]
+    async fn off_on_inverted_pins() {
+        for inverted in [
+            &[("INVERT_PWM", "true")][..],
+            &[("INVERT_CHANNELS", "red,green,blue,white")][..],
+        ] {
+            let config = Config::for_tests(&[inverted, &[("PIN_W", "23")]].concat());
+            let shown = Recording::default();
+            let mut output = Output::with_light(Box::new(shown.clone()));
+
+            // both turning the light off and blanking it drive every inverted pin fully high
+            State::default()
+                .apply(&config, &mut output)
+                .await
+                .expect("apply failed");
+            blank_output(&config, &mut output).await.expect("blank failed");
+
+            let writes = shown.writes.lock().unwrap();
+            assert_eq!(writes.len(), 2);
+            for (rgb, white) in writes.iter() {
+                assert_eq!((*rgb, *white), (Rgb::new(1., 1., 1.), Some(1.)), "{inverted:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn invert_combinations() {
+        use Channel::*;
+
+        let cases = [
+            // (INVERT_PWM, INVERT_CHANNELS, the inverted channels)
+            ("false", "", &[][..]),
+            ("true", "", &[Red, Green, Blue, White][..]),
+            ("false", "red", &[Red][..]),
+            ("false", "green,white", &[Green, White][..]),
+            ("false", "red,green,blue", &[Red, Green, Blue][..]),
+            // inverting everything covers the channels inverted on their own too
+            ("true", "blue", &[Red, Green, Blue, White][..]),
+        ];
+
+        for (invert_pwm, invert_channels, inverted) in cases {
+            let config = Config::for_tests(&[
+                ("PIN_W", "23"),
+                ("INVERT_PWM", invert_pwm),
+                ("INVERT_CHANNELS", invert_channels),
+            ]);
+            let case = (invert_pwm, invert_channels);
+
+            for channel in [Red, Green, Blue, White] {
+                assert_eq!(
+                    config.is_inverted(channel),
+                    inverted.contains(&channel),
+                    "{case:?}: {channel:?}"
+                );
+            }
+
+            let shown = Recording::default();
+            let mut output = Output::with_light(Box::new(shown.clone()));
+            // the white shared by the channels, 0.2, is split off to the white channel
+            write_rgb_to_blaster(Rgb::new(0.2, 0.4, 0.6), &config, &mut output)
+                .await
+                .expect("write failed");
+
+            let duty = |channel, duty: f32| if inverted.contains(&channel) { 1. - duty } else { duty };
+            let (rgb, white) = shown.writes.lock().unwrap()[0];
+            let expected = [duty(Red, 0.), duty(Green, 0.2), duty(Blue, 0.4), duty(White, 0.2)];
+            let written = [rgb.red, rgb.green, rgb.blue, white.expect("no white written")];
+            for (written, expected) in written.into_iter().zip(expected) {
+                assert!((written - expected).abs() < 1e-6, "{case:?}: {written} {expected}");
+            }
+
+            // the UDP sink and the refresh get the frame as computed, not as inverted
+            assert_eq!(output.last_frame(), Some(Rgb::new(0.2, 0.4, 0.6)), "{case:?}");
+        }
+    }
+}
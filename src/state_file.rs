@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use log::*;
+use tokio::fs;
+
+/// Writes the state to the state file, replacing it atomically by writing a temporary file next to it and renaming it
+/// over the state file, so a crash mid-write never leaves a partial state behind. Failing to write is only logged.
+pub async fn save(path: &Path, state_json: &[u8]) {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    let result = async {
+        fs::write(&temporary, state_json).await?;
+        fs::rename(&temporary, path).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        warn!("Failed to write state file {}: {e}", path.display());
+    }
+}
+
+/// Reads the state stored in the state file, if there is one
+pub async fn load(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match fs::read(path).await {
+        Ok(state_json) => Ok(Some(state_json)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow::anyhow!("failed to read state file {}: {e}", path.display())),
+    }
+}
@@ -0,0 +1,45 @@
+use chrono::NaiveDateTime;
+use log::*;
+
+use crate::{
+    config::Config,
+    schedule,
+    state::{State, MAX_MIREDS},
+    ControlMessage, OnState,
+};
+
+/// Tracks the daily sunrise, which brings the light up from off to full brightness in a warm white over a long
+/// transition, like a wake-up light.
+#[derive(Debug, Default)]
+pub struct Sunrise {
+    running: bool,
+}
+
+impl Sunrise {
+    /// When the next sunrise starts, if it's configured
+    pub fn next(config: &Config) -> Option<NaiveDateTime> {
+        config.sunrise_time.map(schedule::next_daily)
+    }
+
+    /// Starts the sunrise and returns the command to apply for it
+    pub fn start(&mut self, config: &Config) -> ControlMessage {
+        info!("Starting sunrise over {}s", config.sunrise_duration);
+        self.running = true;
+
+        ControlMessage {
+            state: Some(OnState::On),
+            brightness: Some(u8::MAX),
+            color_temp: Some(MAX_MIREDS),
+            transition: Some(config.sunrise_duration),
+            ..Default::default()
+        }
+    }
+
+    /// Stops a sunrise in progress where it got to, when the light's commanded while it rises
+    pub fn cancel(&mut self, state: &mut State) {
+        if std::mem::take(&mut self.running) && state.in_transition() {
+            info!("Cancelling sunrise");
+            state.halt_transition();
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+
+/// A template for the shape of the published state, for consumers that expect a different shape than the state's own
+/// serialisation. The template is a JSON object whose values are either nested objects or strings naming a field of
+/// the state, with nested fields separated by dots (e.g. `color.h`). For example, `{"power": "state", "light":
+/// {"level": "brightness"}}` publishes the state's `state` field as `power`, and its `brightness` field as `level`
+/// inside a `light` object.
+#[derive(Debug, Clone)]
+pub struct StateTemplate(Map<String, Value>);
+
+impl<'de> Deserialize<'de> for StateTemplate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // the template is given as a JSON string, so it can be set from an environment variable
+        let value = String::deserialize(deserializer)?;
+
+        match serde_json::from_str(&value).map_err(serde::de::Error::custom)? {
+            Value::Object(template) => Ok(Self(template)),
+            _ => Err(serde::de::Error::custom("state template has to be a JSON object")),
+        }
+    }
+}
+
+impl StateTemplate {
+    /// Checks that every field the template references exists in the given serialised state
+    pub fn validate(&self, state: &Value) -> anyhow::Result<()> {
+        fn validate_object(template: &Map<String, Value>, state: &Value) -> anyhow::Result<()> {
+            for (key, value) in template {
+                match value {
+                    Value::Object(nested) => validate_object(nested, state)?,
+                    Value::String(path) => {
+                        anyhow::ensure!(
+                            lookup(state, path).is_some(),
+                            "state template field '{key}' references unknown state field '{path}'"
+                        )
+                    }
+                    _ => anyhow::bail!("state template field '{key}' has to be a field name or a nested object"),
+                }
+            }
+
+            Ok(())
+        }
+
+        validate_object(&self.0, state)
+    }
+
+    /// Builds the templated shape from the given serialised state
+    pub fn render(&self, state: &Value) -> Value {
+        fn render_object(template: &Map<String, Value>, state: &Value) -> Value {
+            template
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::Object(nested) => render_object(nested, state),
+                        Value::String(path) => lookup(state, path).cloned().unwrap_or(Value::Null),
+                        _ => Value::Null,
+                    };
+
+                    (key.clone(), value)
+                })
+                .collect::<Map<_, _>>()
+                .into()
+        }
+
+        render_object(&self.0, state)
+    }
+}
+
+fn lookup<'a>(state: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(state, |value, field| value.get(field))
+}